@@ -99,6 +99,12 @@ fn main() {
         compile.c_file("parsers/css/scanner.c");
     }
 
+    #[cfg(feature = "lang_embedded_template")]
+    {
+        compile.c_file("parsers/embedded-template/parser.c");
+        compile.cpp_file("parsers/embedded-template/scanner.cc");
+    }
+
     #[cfg(feature = "lang_go")]
     {
         compile.c_file("parsers/go/parser.c");
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use failure::{Error, format_err};
+use serde_json::{json, Value};
+
+use hornbeam::{Language, Parser, Tree, OwnedTree, Point, Range, InputEdit, Symbol, SymbolKind, Highlighter, Scope, FoldingRange, LineIndex, ColumnUnit};
+
+const TOKEN_TYPES: &[&str] = &[
+    "keyword",
+    "string",
+    "number",
+    "enumMember",
+    "comment",
+    "function",
+    "type",
+    "property",
+    "operator",
+];
+
+fn token_type(scope: Scope) -> Option<usize> {
+    Some(match scope {
+        Scope::Keyword => 0,
+        Scope::String => 1,
+        Scope::Number => 2,
+        Scope::Constant => 3,
+        Scope::Comment => 4,
+        Scope::Function => 5,
+        Scope::Type => 6,
+        Scope::Property => 7,
+        Scope::Operator => 8,
+        Scope::Punctuation => return None,
+    })
+}
+
+fn symbol_kind_number(kind: SymbolKind) -> u32 {
+    match kind {
+        SymbolKind::Module => 2,
+        SymbolKind::Class | SymbolKind::Impl | SymbolKind::Type => 5,
+        SymbolKind::Method => 6,
+        SymbolKind::Enum => 10,
+        SymbolKind::Interface => 11,
+        SymbolKind::Function | SymbolKind::Macro => 12,
+        SymbolKind::Constant => 14,
+        SymbolKind::Struct => 23,
+    }
+}
+
+// LSP positions count UTF-16 code units, while tree-sitter points count bytes.
+struct Lines<'a>(LineIndex<'a>);
+
+impl<'a> Lines<'a> {
+    fn new(text: &'a str) -> Lines<'a> {
+        Lines(LineIndex::new(text))
+    }
+
+    // The byte offset and point of an LSP position.
+    fn locate(&self, pos: &Value) -> (usize, Point) {
+        let row = pos["line"].as_u64().unwrap_or(0) as usize;
+        let units = pos["character"].as_u64().unwrap_or(0) as usize;
+        let point = self.0.point_at(row, units, ColumnUnit::Utf16);
+        (self.0.offset(point), point)
+    }
+
+    fn to_lsp(&self, p: Point) -> Value {
+        json!({ "line": p.row, "character": self.0.column(p, ColumnUnit::Utf16) })
+    }
+
+    fn range(&self, r: &Range) -> Value {
+        json!({ "start": self.to_lsp(r.start_point), "end": self.to_lsp(r.end_point) })
+    }
+}
+
+fn end_point(start: Point, inserted: &str) -> Point {
+    match inserted.rfind('\n') {
+        Some(i) => Point::new(start.row + inserted.matches('\n').count(), inserted.len() - i - 1),
+        None => Point::new(start.row, start.column + inserted.len()),
+    }
+}
+
+fn language_for(uri: &str, language_id: &str) -> Option<Language> {
+    let lang = language_id.parse::<Language>().ok().or_else(|| {
+        let ext = Path::new(uri).extension()?.to_str()?;
+        Language::from_extension(ext).ok()
+    })?;
+
+    if lang.is_compiled_in() {
+        Some(lang)
+    } else {
+        None
+    }
+}
+
+struct Server {
+    documents: HashMap<String, OwnedTree>,
+    parsers: HashMap<Language, Parser>,
+    highlighters: HashMap<Language, Highlighter>,
+    out: io::Stdout,
+}
+
+impl Server {
+    fn send(&mut self, msg: Value) -> Result<(), Error> {
+        let body = serde_json::to_string(&msg)?;
+        let mut out = self.out.lock();
+        write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    fn parser(&mut self, lang: Language) -> &mut Parser {
+        self.parsers.entry(lang).or_insert_with(|| Parser::new(lang))
+    }
+
+    fn open(&mut self, params: &Value) -> Result<(), Error> {
+        let doc = &params["textDocument"];
+        let uri = doc["uri"].as_str().unwrap_or("").to_string();
+        let lang = match language_for(&uri, doc["languageId"].as_str().unwrap_or("")) {
+            Some(lang) => lang,
+            None => return Ok(()),
+        };
+
+        let text = doc["text"].as_str().unwrap_or("").to_string();
+        let tree = self.parser(lang).parse_owned(text)?;
+        self.documents.insert(uri.clone(), tree);
+        self.publish_diagnostics(&uri)
+    }
+
+    fn change(&mut self, params: &Value) -> Result<(), Error> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+        let (lang, mut text, mut tree) = match self.documents.get(&uri) {
+            Some(doc) => (doc.language(), doc.text().to_string(), doc.detach()),
+            None => return Ok(()),
+        };
+
+        for change in params["contentChanges"].as_array().map_or(&[][..], |c| &c[..]) {
+            let inserted = change["text"].as_str().unwrap_or("");
+
+            if change["range"].is_null() {
+                text = inserted.to_string();
+                tree = self.parser(lang).parse(&text)?.detach();
+                continue;
+            }
+
+            let (start_byte, start_position, old_end_byte, old_end_position) = {
+                let lines = Lines::new(&text);
+                let (start_byte, start_position) = lines.locate(&change["range"]["start"]);
+                let (old_end_byte, old_end_position) = lines.locate(&change["range"]["end"]);
+                (start_byte, start_position, old_end_byte, old_end_position)
+            };
+            if old_end_byte < start_byte {
+                return Err(format_err!("change to {} ends before it starts", uri));
+            }
+
+            text.replace_range(start_byte..old_end_byte, inserted);
+            tree.edit(&InputEdit {
+                start_byte,
+                old_end_byte,
+                new_end_byte: start_byte + inserted.len(),
+                start_position,
+                old_end_position,
+                new_end_position: end_point(start_position, inserted),
+            });
+        }
+
+        let tree = self.parser(lang).reparse_owned(text, &tree)?;
+        self.documents.insert(uri.clone(), tree);
+        self.publish_diagnostics(&uri)
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) -> Result<(), Error> {
+        let diagnostics = {
+            let doc = &self.documents[uri];
+            let tree = doc.tree();
+            let lines = Lines::new(doc.text());
+
+            tree.nodes().filter(|n| n.is_error() || n.is_missing()).map(|n| {
+                let message = if n.is_missing() {
+                    format!("missing {}", n.kind_name())
+                } else {
+                    "syntax error".to_string()
+                };
+                json!({
+                    "range": lines.range(&n.range()),
+                    "severity": 1,
+                    "source": "hornbeam",
+                    "message": message,
+                })
+            }).collect::<Vec<_>>()
+        };
+
+        self.send_diagnostics(uri, diagnostics)
+    }
+
+    fn send_diagnostics(&mut self, uri: &str, diagnostics: Vec<Value>) -> Result<(), Error> {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }))
+    }
+
+    fn document_symbols(&self, tree: &Tree, lines: &Lines) -> Value {
+        fn convert(s: &Symbol, lines: &Lines) -> Value {
+            json!({
+                "name": s.name,
+                "kind": symbol_kind_number(s.kind),
+                "range": lines.range(&s.range),
+                "selectionRange": lines.range(&s.selection_range),
+                "children": s.children.iter().map(|c| convert(c, lines)).collect::<Vec<_>>(),
+            })
+        }
+
+        Value::Array(Symbol::outline(tree).iter().map(|s| convert(s, lines)).collect())
+    }
+
+    fn folding_ranges(&self, tree: &Tree) -> Value {
+        Value::Array(FoldingRange::compute(tree).iter()
+            .map(|f| json!({ "startLine": f.start_row, "endLine": f.end_row, "kind": f.kind.name() }))
+            .collect())
+    }
+
+    fn selection_ranges(&self, tree: &Tree, lines: &Lines, params: &Value) -> Value {
+        let positions = params["positions"].as_array().map_or(&[][..], |p| &p[..]);
+        Value::Array(positions.iter().map(|pos| {
+            let (_, point) = lines.locate(pos);
+            hornbeam::selection_chain(tree, point).iter().rev().fold(Value::Null, |parent, r| {
+                let mut range = json!({ "range": lines.range(r) });
+                if !parent.is_null() {
+                    range["parent"] = parent;
+                }
+                range
+            })
+        }).collect())
+    }
+
+    fn semantic_tokens(&mut self, uri: &str) -> Result<Value, Error> {
+        let lang = self.documents[uri].language();
+        if let Entry::Vacant(entry) = self.highlighters.entry(lang) {
+            entry.insert(Highlighter::new(lang)?);
+        }
+
+        let doc = &self.documents[uri];
+        let tree = doc.tree();
+        let lines = Lines::new(doc.text());
+        let spans = self.highlighters[&lang].highlight(&tree);
+
+        let mut data = Vec::new();
+        let (mut prev_row, mut prev_start) = (0, 0);
+        for span in spans {
+            let ty = match token_type(span.scope) {
+                Some(ty) => ty,
+                None => continue,
+            };
+
+            // Tokens may not span lines, so split multi-line strings and comments.
+            let mut offset = span.start;
+            for piece in doc.text()[span.start..span.end].split('\n') {
+                let point = lines.0.point(offset);
+                let row = point.row;
+                let start = lines.0.column(point, ColumnUnit::Utf16);
+                let len = hornbeam::column_width(piece, ColumnUnit::Utf16);
+                offset += piece.len() + 1;
+                if len == 0 {
+                    continue;
+                }
+
+                let delta_start = if row == prev_row { start - prev_start } else { start };
+                data.extend_from_slice(&[row - prev_row, delta_start, len, ty, 0]);
+                prev_row = row;
+                prev_start = start;
+            }
+        }
+
+        Ok(json!({ "data": data }))
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, Error> {
+        if method == "initialize" {
+            return Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": { "openClose": true, "change": 2 },
+                    "documentSymbolProvider": true,
+                    "foldingRangeProvider": true,
+                    "selectionRangeProvider": true,
+                    "semanticTokensProvider": {
+                        "legend": { "tokenTypes": TOKEN_TYPES, "tokenModifiers": [] },
+                        "full": true,
+                    },
+                },
+                "serverInfo": { "name": "hornbeam-lsp" },
+            }));
+        }
+
+        if method == "shutdown" {
+            return Ok(Value::Null);
+        }
+
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+        if !self.documents.contains_key(&uri) {
+            return Ok(Value::Null);
+        }
+
+        if method == "textDocument/semanticTokens/full" {
+            return self.semantic_tokens(&uri);
+        }
+
+        let doc = &self.documents[&uri];
+        let tree = doc.tree();
+        let lines = Lines::new(doc.text());
+
+        Ok(match method {
+            "textDocument/documentSymbol" => self.document_symbols(&tree, &lines),
+            "textDocument/foldingRange" => self.folding_ranges(&tree),
+            "textDocument/selectionRange" => self.selection_ranges(&tree, &lines, params),
+            _ => return Err(format_err!("unsupported method '{}'", method)),
+        })
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) -> Result<(), Error> {
+        match method {
+            "textDocument/didOpen" => self.open(params)?,
+            "textDocument/didChange" => self.change(params)?,
+            "textDocument/didClose" => {
+                if let Some(uri) = params["textDocument"]["uri"].as_str() {
+                    self.documents.remove(uri);
+                    // Otherwise the client keeps showing the closed document's errors.
+                    self.send_diagnostics(uri, Vec::new())?;
+                }
+            }
+            "exit" => std::process::exit(0),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn read_message(input: &mut impl BufRead) -> Result<Option<Value>, Error> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        let mut parts = line.splitn(2, ':');
+        if parts.next() == Some("Content-Length") {
+            length = Some(parts.next().unwrap_or("").trim().parse::<usize>()?);
+        }
+    }
+
+    let length = length.ok_or_else(|| format_err!("missing Content-Length header"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+fn main() -> Result<(), Error> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+
+    let mut server = Server {
+        documents: HashMap::new(),
+        parsers: HashMap::new(),
+        highlighters: HashMap::new(),
+        out: io::stdout(),
+    };
+
+    while let Some(msg) = read_message(&mut input)? {
+        let method = msg["method"].as_str().unwrap_or("").to_string();
+        let params = &msg["params"];
+
+        if msg.get("id").is_none() {
+            if let Err(e) = server.notification(&method, params) {
+                eprintln!("hornbeam-lsp: {}: {}", method, e);
+            }
+            continue;
+        }
+
+        let response = match server.request(&method, params) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": msg["id"], "result": result }),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": msg["id"],
+                "error": { "code": -32601, "message": e.to_string() },
+            }),
+        };
+        server.send(response)?;
+    }
+
+    Ok(())
+}
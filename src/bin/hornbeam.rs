@@ -1,12 +1,15 @@
-use std::str::FromStr;
-use failure::Error;
+use failure::{Error, format_err};
 use std::path::{Path, PathBuf};
-use std::io::Read;
-use std::fs::File;
+use std::io::{self, Write};
+use std::fs::{self, File};
+use std::time::Duration;
 use structopt::StructOpt as StructOptTrait;
 use structopt_derive::StructOpt;
 
-use hornbeam::{Language, Parser, Node, Kind, Child, Transform};
+use std::collections::HashMap;
+use walkdir::WalkDir;
+
+use hornbeam::{Language, Parser, ParseError, LogType, Node, Child, Transform, LayeredParser, Injection, Highlighter, Format, Tag, TaggedFile, Symbol, Formatter, Finder, Schema, Literals, TextMode, Bindings, Linter, LintedFile, Baseline, Severity, Diagnostic, ReportFormat, Decoded, FunctionMetrics, MeasuredFile, CloneDetector, CloneOptions, CloneGroup, LineIndex};
 
 #[derive(StructOpt)]
 struct ParseArgs {
@@ -31,156 +34,391 @@ struct ParseArgs {
     #[structopt(short = "k", long = "kind")]
     kind: Option<String>,
 
+    // Comma-separated leaf kinds (or `*` for all leaves) whose text must match the example's.
+    #[structopt(long = "literal")]
+    literal: Option<String>,
+
+    #[structopt(long = "text-match", default_value = "exact")]
+    text_match: TextMode,
+
+    // Constraints on matches, each either `kind:` and a kind name, or an example.
+    #[structopt(long = "inside", number_of_values = 1)]
+    inside: Vec<String>,
+
+    #[structopt(long = "not-inside", number_of_values = 1)]
+    not_inside: Vec<String>,
+
+    #[structopt(long = "has", number_of_values = 1)]
+    has: Vec<String>,
+
     #[structopt(short = "g", long = "glob")]
     glob: Option<String>,
 
+    // Report matches in this format rather than printing them.
+    #[structopt(long = "report")]
+    report: Option<ReportFormat>,
+
     #[structopt(short = "t", long = "tree")]
     tree: bool,
 
     #[structopt(long = "transform")]
     transform: Option<PathBuf>,
 
+    #[structopt(short = "i", long = "inject")]
+    inject: bool,
+
+    #[structopt(long = "in")]
+    inner_lang: Option<Language>,
+
+    // Give up on any file that takes longer than this many milliseconds to parse.
+    #[structopt(long = "timeout")]
+    timeout: Option<u64>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-fn read_file(path: impl AsRef<Path>) -> Result<String, Error> {
-    let mut res = String::new();
-    File::open(path)?.read_to_string(&mut res)?;
-    Ok(res)
+#[derive(StructOpt)]
+enum Command {
+    #[structopt(name = "highlight")]
+    Highlight(HighlightArgs),
+
+    #[structopt(name = "tags")]
+    Tags(TagsArgs),
+
+    #[structopt(name = "outline")]
+    Outline(OutlineArgs),
+
+    #[structopt(name = "format")]
+    Format(FormatArgs),
+
+    #[structopt(name = "lint")]
+    Lint(LintArgs),
+
+    #[structopt(name = "metrics")]
+    Metrics(MetricsArgs),
+
+    #[structopt(name = "clones")]
+    Clones(ClonesArgs),
+
+    #[structopt(name = "debug")]
+    Debug(DebugArgs),
 }
 
-fn find_example<'a>(node: Node<'a>, ex: &str) -> Option<Node<'a>> {
-    if node.text().contains(ex) {
-        for ch in node.children() {
-            match ch {
-                Child::Node(ch) => {
-                    if let Some(n) = find_example(ch, ex) {
-                        return Some(n);
-                    }
-                }
-                Child::Text(text) => {}
-            }
-        }
-        Some(node.clone())
-    } else {
-        None
+#[derive(StructOpt)]
+struct HighlightArgs {
+    #[structopt(long = "lang")]
+    lang: Option<Language>,
+
+    #[structopt(short = "f", long = "format", default_value = "ansi")]
+    format: Format,
+
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+}
+
+// Reads a file in whatever encoding it's in, warning if any of it had to be replaced.
+fn read_file(path: impl AsRef<Path>, lang: Language) -> Result<Decoded, Error> {
+    let path = path.as_ref();
+    let res = hornbeam::decode(&fs::read(path)?, Some(lang));
+    if let Some(warning) = &res.warning {
+        eprintln!("warning: {}: {}", path.display(), warning);
     }
+    Ok(res)
 }
 
-#[derive(Eq, PartialEq)]
-struct Schema {
-    kind: Kind,
-    children: Vec<Schema>,
+#[derive(StructOpt)]
+struct TagsArgs {
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+
+    #[structopt(short = "e", long = "etags")]
+    etags: bool,
+
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    output: Option<PathBuf>,
 }
 
-impl Schema {
-    fn from(node: Node) -> Schema {
-        Schema {
-            kind: node.kind(),
-            children: node.nodes().map(Schema::from).collect()
-        }
-    }
+#[derive(StructOpt)]
+struct OutlineArgs {
+    #[structopt(long = "lang")]
+    lang: Option<Language>,
 
-    fn matches(&self, node: &Node) -> bool {
-        if self.kind != node.kind() {
-            return false;
-        }
+    #[structopt(long = "json")]
+    json: bool,
 
-        let mut mch = self.children.iter();
-        let mut nch = node.nodes();
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+}
 
-        loop {
-            match (mch.next(), nch.next()) {
-                (Some(m), Some(n)) => {
-                    if !m.matches(&n) {
-                        return false;
-                    } else {
-                        // fallthrough
-                    }
-                }
-                (None, None) => return true,
-                _ => return false,
-            }
+#[derive(StructOpt)]
+struct FormatArgs {
+    #[structopt(long = "lang")]
+    lang: Option<Language>,
+
+    #[structopt(short = "w", long = "width")]
+    width: Option<usize>,
+
+    #[structopt(long = "indent")]
+    indent: Option<usize>,
+
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+}
+
+#[derive(StructOpt)]
+struct LintArgs {
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+
+    #[structopt(short = "c", long = "config", parse(from_os_str), default_value = "hornbeam-lint.json")]
+    config: PathBuf,
+
+    #[structopt(short = "f", long = "format", default_value = "text")]
+    format: ReportFormat,
+
+    // Only report diagnostics that aren't in this file.
+    #[structopt(long = "baseline", parse(from_os_str))]
+    baseline: Option<PathBuf>,
+
+    #[structopt(long = "write-baseline", parse(from_os_str))]
+    write_baseline: Option<PathBuf>,
+
+    // Rewrite files with the rules' fixes applied.
+    #[structopt(long = "fix")]
+    fix: bool,
+}
+
+#[derive(StructOpt)]
+struct MetricsArgs {
+    #[structopt(long = "lang")]
+    lang: Option<Language>,
+
+    #[structopt(long = "json")]
+    json: bool,
+
+    // A file, or a directory to measure every file in.
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+}
+
+#[derive(StructOpt)]
+struct ClonesArgs {
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+
+    #[structopt(long = "min-tokens", default_value = "50")]
+    min_tokens: usize,
+
+    // Also report clones that differ in names and literals.
+    #[structopt(long = "renamed")]
+    renamed: bool,
+
+    #[structopt(long = "json")]
+    json: bool,
+}
+
+#[derive(StructOpt)]
+struct DebugArgs {
+    #[structopt(long = "lang")]
+    lang: Option<Language>,
+
+    // Print each step of the lexer and parser.
+    #[structopt(long = "log")]
+    log: bool,
+
+    #[structopt(flatten)]
+    #[cfg_attr(not(unix), allow(dead_code))]
+    graphs: GraphArgs,
+
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+}
+
+// Tree-sitter can only write DOT graphs to a file descriptor, so there's no `--dot` elsewhere.
+#[cfg(unix)]
+#[derive(StructOpt)]
+struct GraphArgs {
+    // Write the parse stack after each step to this file, as DOT graphs.
+    #[structopt(long = "dot", parse(from_os_str))]
+    dot: Option<PathBuf>,
+}
+
+#[cfg(not(unix))]
+#[derive(StructOpt)]
+struct GraphArgs {}
+
+fn new_parser(lang: Language, timeout: Option<Duration>) -> Parser {
+    let mut parser = Parser::new(lang);
+    parser.set_timeout(timeout);
+    parser
+}
+
+fn file_language(lang: Option<Language>, path: &Path) -> Result<Language, Error> {
+    if let Some(lang) = lang {
+        return Ok(lang);
+    }
+
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    Language::from_extension(ext)
+}
+
+// The files under `dir` in a compiled-in language, in a stable order.
+fn source_files(dir: &Path) -> Result<Vec<(PathBuf, Language)>, Error> {
+    let mut res = Vec::new();
+    for entry in WalkDir::new(dir).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        let entry = entry?;
+        match file_language(None, entry.path()) {
+            Ok(lang) if lang.is_compiled_in() && entry.file_type().is_file() => res.push((entry.into_path(), lang)),
+            _ => {}
         }
     }
+    Ok(res)
 }
 
-enum Finder {
-    Kind(Kind),
-    Schema(Schema),
+fn finder_from_args(parser: &mut Parser, args: &ParseArgs) -> Result<Finder, Error> {
+    if let Some(kind) = &args.kind {
+        let kind = parser.info.kind_from_name(kind)
+            .ok_or_else(|| format_err!("{:?} has no kind named '{}'", parser.language(), kind))?;
+        return constrain(parser, args, Finder::Kind(kind));
+    }
+
+    if let Some(example) = &args.example {
+        let schema = Schema::from_example(parser, example, args.context.as_deref(), &literals(args))?;
+        eprintln!("syntax: {:?}", schema);
+        return constrain(parser, args, Finder::Schema(schema));
+    }
+
+    Err(format_err!("nothing to find: give --kind or --example"))
 }
 
-impl Finder {
-    fn matches<'a>(&self, node: &Node<'a>) -> bool {
-        match self {
-            Finder::Kind(k) => node.kind() == *k,
-            Finder::Schema(s) => s.matches(node),
-        }
+fn literals(args: &ParseArgs) -> Literals {
+    Literals {
+        kinds: args.literal.as_ref().map_or(Vec::new(), |l| l.split(',').map(|k| k.trim().to_string()).collect()),
+        mode: args.text_match,
     }
-    fn from_args(parser: &mut Parser, args: &ParseArgs) -> Finder {
-        if let Some(kind) = &args.kind {
-            let kind = parser.info.kind_from_name(&kind).unwrap();
-            return Finder::Kind(kind);
-        }
+}
 
-        if let Some(example) = &args.example {
-            let full = if let Some(context) = &args.context {
-                context.replace("@@", &example)
-            } else {
-                example.clone()
-            };
+fn relation_finder(parser: &mut Parser, args: &ParseArgs, pattern: &str) -> Result<Finder, Error> {
+    Finder::from_pattern(parser, pattern, None, &literals(args))
+        .map_err(|e| format_err!("bad pattern '{}': {}", pattern, e))
+}
 
-            let ex = parser.parse(&full);
-            let ex = find_example(ex.root(), &example).unwrap();
-            println!("syntax: {:?}", ex);
-            let schema = Schema::from(ex);
+fn constrain(parser: &mut Parser, args: &ParseArgs, mut finder: Finder) -> Result<Finder, Error> {
+    for inside in &args.inside {
+        finder = finder.inside(relation_finder(parser, args, inside)?);
+    }
+    for not_inside in &args.not_inside {
+        finder = finder.not_inside(relation_finder(parser, args, not_inside)?);
+    }
+    for has in &args.has {
+        finder = finder.has(relation_finder(parser, args, has)?);
+    }
+    Ok(finder)
+}
 
-            return Finder::Schema(schema)
-        }
+fn print_match(node: &Node, bindings: &Bindings) {
+    println!("{}", node.text());
+    for (name, text) in bindings {
+        println!("  ${} = {}", name, text);
+    }
+}
 
-        panic!();
+fn match_diagnostic(node: &Node, bindings: &Bindings) -> Diagnostic {
+    let mut message = format!("match of {}", node.kind_name());
+    for (name, text) in bindings {
+        message.push_str(&format!(", ${} = {}", name, text));
     }
+    Diagnostic::new("find", Severity::Note, message, node)
 }
 
 enum Action {
     Replay,
     Find(Finder),
+    FindLayered(Language, Finder),
     Tree,
 }
 
 impl Action {
-    fn from_args(parser: &mut Parser, args: &ParseArgs) -> Action {
+    fn from_args(parser: &mut Parser, args: &ParseArgs) -> Result<Action, Error> {
         if args.replay {
-            return Action::Replay;
+            return Ok(Action::Replay);
         }
         if args.tree {
-            return Action::Tree;
+            return Ok(Action::Tree);
+        }
+        if args.inject {
+            let mut inner = Parser::new(args.inner_lang.unwrap_or(parser.language()));
+            let finder = finder_from_args(&mut inner, args)?;
+            return Ok(Action::FindLayered(inner.language(), finder));
+        }
+        Ok(Action::Find(finder_from_args(parser, args)?))
+    }
+
+    // Templates get the code language their extension implies.
+    fn layered_parser(parser: &Parser, path: &Path) -> LayeredParser {
+        let mut layered = LayeredParser::new(parser.language());
+        layered.set_timeout(parser.timeout());
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        if let Some(code) = Injection::template_language(ext) {
+            layered.set_injections(Language::EmbeddedTemplate, Injection::template(code));
         }
-        Action::Find(Finder::from_args(parser, args))
+        layered
     }
 
-    fn apply(&self, parser: &mut Parser, text: &str) {
+    // Matches as diagnostics, for reports. Only finding has any.
+    fn diagnostics(&self, parser: &mut Parser, path: &Path, text: &str) -> Result<Vec<Diagnostic>, ParseError> {
+        Ok(match self {
+            Action::Find(finder) => {
+                let tree = parser.parse(text)?;
+                tree.nodes()
+                    .filter_map(|n| finder.matches(&n).map(|b| match_diagnostic(&n, &b)))
+                    .collect()
+            }
+            Action::FindLayered(lang, finder) => {
+                let tree = Action::layered_parser(parser, path).parse(text)?;
+                tree.nodes()
+                    .filter(|n| n.language() == *lang)
+                    .filter_map(|n| finder.matches(&n).map(|b| match_diagnostic(&n, &b)))
+                    .collect()
+            }
+            Action::Replay | Action::Tree => Vec::new(),
+        })
+    }
+
+    fn apply(&self, parser: &mut Parser, path: &Path, text: &str) -> Result<(), ParseError> {
         match self {
             Action::Replay => {
                 let text = text.replace('\n', " ");
                 for i in 0..text.len() + 1 {
                     let prefix = &text[0..i];
-                    println!("{} {:?}", prefix, parser.parse(&prefix));
+                    println!("{} {:?}", prefix, parser.parse(prefix)?);
                 }
             }
             Action::Find(finder) => {
-                let tree = parser.parse(&text);
+                let tree = parser.parse(text)?;
 
                 for node in tree.nodes() {
-                    if finder.matches(&node) {
-                        println!("{}", node.text());
+                    if let Some(bindings) = finder.matches(&node) {
+                        print_match(&node, &bindings);
+                    }
+                }
+            }
+            Action::FindLayered(lang, finder) => {
+                let tree = Action::layered_parser(parser, path).parse(text)?;
+
+                for node in tree.nodes().filter(|n| n.language() == *lang) {
+                    if let Some(bindings) = finder.matches(&node) {
+                        print_match(&node, &bindings);
                     }
                 }
             }
             Action::Tree => {
-                let tree = parser.parse(&text);
+                let tree = parser.parse(text)?;
                 print_children(&tree.root(), 0);
             }
         }
+        Ok(())
     }
 }
 
@@ -191,23 +429,23 @@ enum Tool {
 }
 
 impl Tool {
-    fn from_args(args: &ParseArgs) -> Tool {
+    fn from_args(args: &ParseArgs) -> Result<Tool, Error> {
         if let Some(lang) = &args.lang {
-            let mut parser = Parser::new(*lang);
+            let mut parser = new_parser(*lang, args.timeout.map(Duration::from_millis));
 
             if args.show_kinds {
-                return Tool::Show(parser);
+                return Ok(Tool::Show(parser));
             }
 
-            let action = Action::from_args(&mut parser, &args);
+            let action = Action::from_args(&mut parser, args)?;
 
-            return Tool::Action(parser, action);
+            return Ok(Tool::Action(parser, action));
         }
 
         if let Some(transform) = &args.transform {
-            return Tool::Transform(Transform::load(transform));
+            return Ok(Tool::Transform(Transform::load(transform)));
         }
-        panic!();
+        Err(format_err!("nothing to do: give a command, --lang or --transform"))
     }
 }
 
@@ -222,25 +460,336 @@ fn print_children<'a>(node: &Node<'a>, indent: usize) {
     println!("{:indent$}End {:?}", "", node.kind(), indent=indent*2);
 }
 
+impl Command {
+    fn run(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        match self {
+            Command::Highlight(args) => {
+                let lang = file_language(args.lang, &args.file)?;
+                let text = read_file(&args.file, lang)?.text;
+                let tree = new_parser(lang, timeout).parse(&text)?;
+                print!("{}", Highlighter::new(lang)?.render(&tree, args.format));
+            }
+            Command::Tags(args) => {
+                let mut parsers = HashMap::new();
+                let mut files = Vec::new();
+
+                for (path, lang) in source_files(&args.dir)? {
+                    let file = match read_file(&path, lang) {
+                        Ok(file) => file,
+                        Err(e) => {
+                            eprintln!("skipping {}: {}", path.display(), e);
+                            continue;
+                        }
+                    };
+
+                    let parser = parsers.entry(lang).or_insert_with(|| new_parser(lang, timeout));
+                    let tree = match parser.parse(&file.text) {
+                        Ok(tree) => tree,
+                        Err(e) => {
+                            eprintln!("skipping {}: {}", path.display(), e);
+                            continue;
+                        }
+                    };
+                    let mut tags = Tag::extract(&tree);
+                    for tag in &mut tags {
+                        tag.line_start = file.original_offset(tag.line_start);
+                    }
+                    files.push(TaggedFile {
+                        path: path.to_string_lossy().into_owned(),
+                        tags,
+                    });
+                }
+
+                let default = if args.etags { "TAGS" } else { "tags" };
+                let mut out = File::create(args.output.as_ref().map_or(Path::new(default), |p| p.as_path()))?;
+                if args.etags {
+                    hornbeam::write_etags(&mut out, &files)?;
+                } else {
+                    hornbeam::write_ctags(&mut out, &files)?;
+                }
+            }
+            Command::Outline(args) => {
+                let lang = file_language(args.lang, &args.file)?;
+                let text = read_file(&args.file, lang)?.text;
+                let tree = new_parser(lang, timeout).parse(&text)?;
+                let outline = Symbol::outline(&tree);
+
+                if args.json {
+                    let lines = LineIndex::new(&text);
+                    let json = outline.iter().map(|s| s.to_json(&lines)).collect::<Vec<_>>();
+                    println!("{}", serde_json::to_string_pretty(&json)?);
+                } else {
+                    print_outline(&outline, 0);
+                }
+            }
+            Command::Format(args) => {
+                let lang = file_language(args.lang, &args.file)?;
+                let text = read_file(&args.file, lang)?.text;
+                let tree = new_parser(lang, timeout).parse(&text)?;
+                if tree.root().has_error() {
+                    return Err(format_err!("not formatting {}, since it has syntax errors", args.file.display()));
+                }
+
+                let mut formatter = Formatter::new(lang);
+                if let Some(width) = args.width {
+                    formatter.width = width;
+                }
+                if let Some(indent) = args.indent {
+                    formatter.indent = indent;
+                }
+                print!("{}", formatter.format(&tree));
+            }
+            Command::Lint(args) => {
+                let linter = Linter::load(&args.config)?;
+                let baseline = match &args.baseline {
+                    Some(path) => Baseline::load(path)?,
+                    None => Baseline::default(),
+                };
+
+                let mut parsers = HashMap::new();
+                let mut all = Vec::new();
+                let mut files = Vec::new();
+
+                for (path, lang) in source_files(&args.dir)? {
+                    if !linter.has_rules_for(lang) {
+                        continue;
+                    }
+
+                    let mut file = match read_file(&path, lang) {
+                        Ok(file) => file,
+                        Err(e) => {
+                            eprintln!("skipping {}: {}", path.display(), e);
+                            continue;
+                        }
+                    };
+
+                    let parser = parsers.entry(lang).or_insert_with(|| new_parser(lang, timeout));
+                    let mut tree = match parser.parse(&file.text) {
+                        Ok(tree) => tree,
+                        Err(e) => {
+                            eprintln!("skipping {}: {}", path.display(), e);
+                            continue;
+                        }
+                    };
+                    let mut linted = LintedFile {
+                        path: path.to_string_lossy().into_owned(),
+                        diagnostics: linter.check(&tree),
+                    };
+                    let mut unfixed = baseline.filter(linted.clone());
+
+                    if args.fix && unfixed.diagnostics.iter().any(|d| d.fix.is_some()) {
+                        // Writing back text with replacement characters would lose the bytes they replaced.
+                        let fixed = if file.lossy {
+                            Err(format_err!("it isn't valid {}", file.encoding.name()))
+                        } else {
+                            Linter::fix(&tree, &unfixed.diagnostics).and_then(|fixed| {
+                                for (d, why) in &fixed.skipped {
+                                    eprintln!("{}:{}: not applying the fix for {}: {}", path.display(), d.range.start_point.row + 1, d.rule, why);
+                                }
+                                file.encode(&fixed.text)
+                            })
+                        };
+                        match fixed {
+                            Ok(bytes) => {
+                                fs::write(&path, bytes)?;
+
+                                // Report what the fixes left, at its positions in the fixed file.
+                                file = read_file(&path, lang)?;
+                                tree = parser.parse(&file.text)?;
+                                linted.diagnostics = linter.check(&tree);
+                                unfixed = baseline.filter(linted.clone());
+                            }
+                            Err(e) => eprintln!("not fixing {}: {}", path.display(), e),
+                        }
+                    }
+
+                    all.push(linted);
+                    let mut linted = unfixed;
+                    for d in &mut linted.diagnostics {
+                        d.range = file.original_range(d.range);
+                    }
+                    files.push(linted);
+                }
+
+                if let Some(path) = &args.write_baseline {
+                    fs::write(path, serde_json::to_string_pretty(&Baseline::new(&all).to_json())?)?;
+                }
+
+                hornbeam::write_report(&mut io::stdout(), args.format, &files)?;
+
+                if files.iter().flat_map(|f| &f.diagnostics).any(|d| d.severity == Severity::Error) {
+                    std::process::exit(1);
+                }
+            }
+            Command::Metrics(args) => {
+                let paths = if args.path.is_file() {
+                    vec![(args.path.clone(), file_language(args.lang, &args.path)?)]
+                } else {
+                    source_files(&args.path)?
+                };
+
+                let mut parsers = HashMap::new();
+                let mut files = Vec::new();
+
+                for (path, lang) in paths {
+                    let file = match read_file(&path, lang) {
+                        Ok(file) => file,
+                        Err(e) => {
+                            eprintln!("skipping {}: {}", path.display(), e);
+                            continue;
+                        }
+                    };
+
+                    let parser = parsers.entry(lang).or_insert_with(|| new_parser(lang, timeout));
+                    let tree = match parser.parse(&file.text) {
+                        Ok(tree) => tree,
+                        Err(e) => {
+                            eprintln!("skipping {}: {}", path.display(), e);
+                            continue;
+                        }
+                    };
+                    files.push(MeasuredFile {
+                        path: path.to_string_lossy().into_owned(),
+                        functions: FunctionMetrics::collect(&tree),
+                    });
+                }
+
+                if args.json {
+                    println!("{}", serde_json::to_string_pretty(&hornbeam::metrics_json(&files))?);
+                } else {
+                    hornbeam::write_metrics_csv(&mut io::stdout(), &files)?;
+                }
+            }
+            Command::Clones(args) => {
+                let mut parsers = HashMap::new();
+                let mut detector = CloneDetector::new(CloneOptions { min_tokens: args.min_tokens, renamed: args.renamed });
+
+                for (path, lang) in source_files(&args.dir)? {
+                    let file = match read_file(&path, lang) {
+                        Ok(file) => file,
+                        Err(e) => {
+                            eprintln!("skipping {}: {}", path.display(), e);
+                            continue;
+                        }
+                    };
+
+                    let parser = parsers.entry(lang).or_insert_with(|| new_parser(lang, timeout));
+                    match parser.parse(&file.text) {
+                        Ok(tree) => detector.add(&path.to_string_lossy(), &tree),
+                        Err(e) => eprintln!("skipping {}: {}", path.display(), e),
+                    }
+                }
+
+                let groups = detector.groups();
+                if args.json {
+                    let json = groups.iter().map(CloneGroup::to_json).collect::<Vec<_>>();
+                    println!("{}", serde_json::to_string_pretty(&json)?);
+                } else {
+                    for group in &groups {
+                        println!("{} clone, {} tokens:", group.kind.name(), group.tokens);
+                        for i in &group.instances {
+                            println!("  {}:{}-{}", i.path, i.range.start_point.row + 1, i.range.end_point.row + 1);
+                        }
+                    }
+                }
+            }
+            Command::Debug(args) => {
+                let lang = file_language(args.lang, &args.file)?;
+                let text = read_file(&args.file, lang)?.text;
+                let mut parser = new_parser(lang, timeout);
+
+                if args.log {
+                    // The logger is called from C, so it mustn't panic if stdout is closed.
+                    let out = io::stdout();
+                    parser.set_logger(Some(Box::new(move |log_type, message| {
+                        let indent = if log_type == LogType::Lex { "  " } else { "" };
+                        let _ = writeln!(out.lock(), "{}{}", indent, message);
+                    })));
+                }
+                #[cfg(unix)]
+                {
+                    if let Some(path) = &args.graphs.dot {
+                        parser.print_dot_graphs(&File::create(path)?);
+                    }
+                }
+
+                let tree = parser.parse(&text);
+                #[cfg(unix)]
+                parser.stop_printing_dot_graphs();
+                println!("{:?}", tree?);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn print_outline(symbols: &[Symbol], indent: usize) {
+    for s in symbols {
+        let p = s.selection_range.start_point;
+        println!("{:indent$}{} {} {}:{}", "", s.kind.name(), s.name, p.row + 1, p.column + 1, indent=indent*2);
+        print_outline(&s.children, indent + 1);
+    }
+}
+
 fn main() {
-    let args = ParseArgs::from_args();
+    if let Err(e) = run(&ParseArgs::from_args()) {
+        eprintln!("error: {}", e);
+        std::process::exit(2);
+    }
+}
+
+fn run(args: &ParseArgs) -> Result<(), Error> {
+    if let Some(command) = &args.command {
+        return command.run(args.timeout.map(Duration::from_millis));
+    }
 
-    let tool = Tool::from_args(&args);
+    let tool = Tool::from_args(args)?;
 
     match tool {
         Tool::Action(mut parser, action) => {
+            let mut paths = Vec::new();
             if let Some(file) = &args.file {
-                let text = read_file(file).unwrap();
-                action.apply(&mut parser, &text);
+                paths.push(file.clone());
             }
-
             if let Some(g) = &args.glob {
-                for entry in glob::glob(g).unwrap() {
-                    let entry = entry.unwrap();
-                    let text = read_file(&entry).unwrap();
-                    action.apply(&mut parser, &text);
+                for entry in glob::glob(g)? {
+                    match entry {
+                        Ok(path) => paths.push(path),
+                        Err(e) => eprintln!("skipping {}: {}", e.path().display(), e.error()),
+                    }
                 }
             }
+
+            let mut files = Vec::new();
+            for path in paths {
+                let file = match read_file(&path, parser.language()) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        eprintln!("skipping {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                let res = if args.report.is_some() {
+                    action.diagnostics(&mut parser, &path, &file.text).map(|mut diagnostics| {
+                        for d in &mut diagnostics {
+                            d.range = file.original_range(d.range);
+                        }
+                        files.push(LintedFile {
+                            path: path.to_string_lossy().into_owned(),
+                            diagnostics,
+                        })
+                    })
+                } else {
+                    action.apply(&mut parser, &path, &file.text)
+                };
+                if let Err(e) = res {
+                    eprintln!("skipping {}: {}", path.display(), e);
+                }
+            }
+
+            if let Some(format) = args.report {
+                hornbeam::write_report(&mut io::stdout(), format, &files)?;
+            }
         }
         Tool::Transform(_transform) => {
             // TODO
@@ -252,4 +801,5 @@ fn main() {
 
         }
     }
+    Ok(())
 }
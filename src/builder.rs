@@ -0,0 +1,587 @@
+use crate::parse::{Language, Parser, Tree, Kind};
+use crate::cst::{Cst, CstNode, CstChild};
+use failure::{Error, format_err};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+// A node to be built: its kind plus the children that can't be inferred from the grammar, i.e.
+// named nodes and any tokens where the grammar offers a choice (like which operator to use).
+// Punctuation, keywords and whitespace are filled in by `TreeBuilder::build`.
+#[derive(Debug, Clone)]
+pub struct NodeBuilder {
+    kind: String,
+    field: Option<String>,
+    text: Option<String>,
+    children: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Node(NodeBuilder),
+    Token(String),
+}
+
+impl NodeBuilder {
+    pub fn new(kind: &str) -> NodeBuilder {
+        NodeBuilder {
+            kind: kind.to_string(),
+            field: None,
+            text: None,
+            children: Vec::new(),
+        }
+    }
+
+    // A node written out as is, e.g. an identifier or a number.
+    pub fn leaf(kind: &str, text: &str) -> NodeBuilder {
+        NodeBuilder {
+            text: Some(text.to_string()),
+            ..NodeBuilder::new(kind)
+        }
+    }
+
+    pub fn child(mut self, child: NodeBuilder) -> NodeBuilder {
+        self.children.push(Part::Node(child));
+        self
+    }
+
+    // Only grammars that declare fields check them; for the others this is the same as `child`.
+    pub fn field(mut self, name: &str, mut child: NodeBuilder) -> NodeBuilder {
+        child.field = Some(name.to_string());
+        self.children.push(Part::Node(child));
+        self
+    }
+
+    pub fn token(mut self, text: &str) -> NodeBuilder {
+        self.children.push(Part::Token(text.to_string()));
+        self
+    }
+}
+
+fn grammar_json(lang: Language) -> Option<&'static str> {
+    match lang {
+        #[cfg(feature = "lang_javascript")]
+        Language::Javascript => Some(include_str!("../parsers/javascript/grammar.json")),
+        #[cfg(feature = "lang_python")]
+        Language::Python => Some(include_str!("../parsers/python/grammar.json")),
+        #[cfg(feature = "lang_rust")]
+        Language::Rust => Some(include_str!("../parsers/rust/grammar.json")),
+        #[cfg(feature = "lang_bash")]
+        Language::Bash => Some(include_str!("../parsers/bash/grammar.json")),
+        #[cfg(feature = "lang_c")]
+        Language::C => Some(include_str!("../parsers/c/grammar.json")),
+        #[cfg(feature = "lang_cpp")]
+        Language::Cpp => Some(include_str!("../parsers/cpp/grammar.json")),
+        #[cfg(feature = "lang_css")]
+        Language::Css => Some(include_str!("../parsers/css/grammar.json")),
+        #[cfg(feature = "lang_embedded_template")]
+        Language::EmbeddedTemplate => Some(include_str!("../parsers/embedded-template/grammar.json")),
+        #[cfg(feature = "lang_go")]
+        Language::Go => Some(include_str!("../parsers/go/grammar.json")),
+        #[cfg(feature = "lang_html")]
+        Language::Html => Some(include_str!("../parsers/html/grammar.json")),
+        #[cfg(feature = "lang_ocaml")]
+        Language::Ocaml => Some(include_str!("../parsers/ocaml/grammar.json")),
+        #[cfg(feature = "lang_php")]
+        Language::Php => Some(include_str!("../parsers/php/grammar.json")),
+        #[cfg(feature = "lang_ruby")]
+        Language::Ruby => Some(include_str!("../parsers/ruby/grammar.json")),
+        #[cfg(feature = "lang_typescript")]
+        Language::Typescript => Some(include_str!("../parsers/typescript/grammar.json")),
+        #[cfg(feature = "lang_agda")]
+        Language::Agda => Some(include_str!("../parsers/agda/grammar.json")),
+        #[cfg(feature = "lang_csharp")]
+        Language::CSharp => Some(include_str!("../parsers/c-sharp/grammar.json")),
+        #[cfg(feature = "lang_julia")]
+        Language::Julia => Some(include_str!("../parsers/julia/grammar.json")),
+        #[cfg(feature = "lang_scala")]
+        Language::Scala => Some(include_str!("../parsers/scala/grammar.json")),
+        _ => None,
+    }
+}
+
+fn is_keyword(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+// One step of a way to build a node: a given child, an inferred token, or a layout change
+// standing in for an external token like Python's `_indent`.
+#[derive(Debug, Clone)]
+enum Piece {
+    Part(usize),
+    Token(String),
+    Newline,
+    Indent,
+    Dedent,
+}
+
+// How far into the given children a partial match got, how many tokens it had to invent, and
+// what it produced.
+type State = (usize, usize, Vec<Piece>);
+
+// Keeps only the cheapest state for each position.
+fn add_state(states: &mut Vec<State>, state: State) -> bool {
+    if let Some(existing) = states.iter_mut().find(|s| s.0 == state.0) {
+        if state.1 < existing.1 {
+            *existing = state;
+            return true;
+        }
+        return false;
+    }
+    states.push(state);
+    true
+}
+
+struct Matcher<'g, 'p> {
+    builder: &'g TreeBuilder,
+    parts: &'p [Part],
+    field: Option<&'g str>,
+    memo: HashMap<(&'g str, usize, Option<&'g str>), Vec<State>>,
+    active: HashSet<(&'g str, usize)>,
+}
+
+impl<'g, 'p> Matcher<'g, 'p> {
+    fn token(&self, value: &str, pos: usize) -> Vec<State> {
+        let mut res = Vec::new();
+        if let Some(Part::Token(t)) = self.parts.get(pos) {
+            if t == value {
+                res.push((pos + 1, 0, vec![Piece::Part(pos)]));
+            }
+        }
+        res.push((pos, 1, vec![Piece::Token(value.to_string())]));
+        res
+    }
+
+    fn node(&self, kind: &str, pos: usize) -> Vec<State> {
+        match self.parts.get(pos) {
+            Some(Part::Node(b)) if b.kind == kind => {
+                let field = b.field.as_deref();
+                if !self.builder.has_fields || field.is_none() || field == self.field {
+                    return vec![(pos + 1, 0, vec![Piece::Part(pos)])];
+                }
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn symbol(&mut self, name: &'g str, pos: usize) -> Vec<State> {
+        let builder = self.builder;
+
+        if builder.externals.contains(name) {
+            let piece = if name.contains("newline") {
+                Piece::Newline
+            } else if name.contains("dedent") {
+                Piece::Dedent
+            } else if name.contains("indent") {
+                Piece::Indent
+            } else {
+                // Something only the external scanner knows how to produce, like an automatic
+                // semicolon; allowed, but only if nothing cheaper fits.
+                return vec![(pos, 2, Vec::new())];
+            };
+            return vec![(pos, 0, vec![piece])];
+        }
+
+        let rule = match builder.rules.get(name) {
+            Some(rule) => rule,
+            None => return Vec::new(),
+        };
+
+        if !name.starts_with('_') && !builder.inline.contains(name) {
+            return self.node(name, pos);
+        }
+
+        let key = (name, pos, self.field);
+        if let Some(res) = self.memo.get(&key) {
+            return res.clone();
+        }
+        if !self.active.insert((name, pos)) {
+            return Vec::new();
+        }
+        let res = self.expand(rule, pos);
+        self.active.remove(&(name, pos));
+        self.memo.insert(key, res.clone());
+        res
+    }
+
+    fn expand(&mut self, rule: &'g Value, pos: usize) -> Vec<State> {
+        let str_of = |key: &str| rule[key].as_str().unwrap_or("");
+
+        match str_of("type") {
+            "BLANK" => vec![(pos, 0, Vec::new())],
+            "STRING" if str_of("value").is_empty() => vec![(pos, 0, Vec::new())],
+            "STRING" => self.token(str_of("value"), pos),
+            "PATTERN" => match self.parts.get(pos) {
+                Some(Part::Token(_)) => vec![(pos + 1, 0, vec![Piece::Part(pos)])],
+                _ => Vec::new(),
+            },
+            "SYMBOL" => self.symbol(rule["name"].as_str().unwrap_or(""), pos),
+            "ALIAS" => {
+                if rule["named"].as_bool() == Some(true) {
+                    self.node(str_of("value"), pos)
+                } else {
+                    self.token(str_of("value"), pos)
+                }
+            }
+            "TOKEN" | "IMMEDIATE_TOKEN" => {
+                let content = &rule["content"];
+                match content["type"].as_str() {
+                    Some("STRING") => self.expand(content, pos),
+                    _ => match self.parts.get(pos) {
+                        Some(Part::Token(_)) => vec![(pos + 1, 0, vec![Piece::Part(pos)])],
+                        _ => Vec::new(),
+                    },
+                }
+            }
+            "PREC" | "PREC_LEFT" | "PREC_RIGHT" | "PREC_DYNAMIC" => self.expand(&rule["content"], pos),
+            "FIELD" => {
+                let outer = self.field;
+                self.field = rule["name"].as_str();
+                let res = self.expand(&rule["content"], pos);
+                self.field = outer;
+                res
+            }
+            "SEQ" => {
+                let mut states = vec![(pos, 0, Vec::new())];
+                for member in rule["members"].as_array().into_iter().flatten() {
+                    let mut next = Vec::new();
+                    for (p, cost, pieces) in states {
+                        for (p2, cost2, pieces2) in self.expand(member, p) {
+                            let mut all = pieces.clone();
+                            all.extend(pieces2);
+                            add_state(&mut next, (p2, cost + cost2, all));
+                        }
+                    }
+                    states = next;
+                    if states.is_empty() {
+                        break;
+                    }
+                }
+                states
+            }
+            "CHOICE" => {
+                let mut states = Vec::new();
+                for member in rule["members"].as_array().into_iter().flatten() {
+                    for state in self.expand(member, pos) {
+                        add_state(&mut states, state);
+                    }
+                }
+                states
+            }
+            "REPEAT" | "REPEAT1" => {
+                let content = &rule["content"];
+                let mut states = if str_of("type") == "REPEAT" { vec![(pos, 0, Vec::new())] } else { Vec::new() };
+                let mut frontier = vec![(pos, 0, Vec::new())];
+
+                while !frontier.is_empty() {
+                    let mut next = Vec::new();
+                    for (p, cost, pieces) in frontier {
+                        for (p2, cost2, pieces2) in self.expand(content, p) {
+                            let mut all = pieces.clone();
+                            all.extend(pieces2);
+                            let state = (p2, cost + cost2, all);
+                            if add_state(&mut states, state.clone()) {
+                                next.push(state);
+                            }
+                        }
+                    }
+                    frontier = next;
+                }
+                states
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+// A node while it's being built, before whitespace has been decided.
+enum Built {
+    Node(&'static str, Kind, Vec<Built>),
+    Leaf(&'static str, Kind, String),
+    Token(String),
+    Newline,
+    Indent,
+    Dedent,
+}
+
+struct Layout<'a> {
+    indent: &'a str,
+    level: usize,
+    newline: bool,
+    // The last character written, and whether it ended a keyword token.
+    prev: Option<(char, bool)>,
+    // Whether the whitespace before the next text has already been written.
+    spaced: bool,
+}
+
+impl<'a> Layout<'a> {
+    fn space(&mut self, next: &str, keyword: bool, out: &mut Vec<CstChild>) {
+        if self.spaced {
+            self.spaced = false;
+            return;
+        }
+
+        let first = next.chars().next().unwrap_or(' ');
+        let space = match self.prev {
+            _ if self.newline => {
+                self.newline = false;
+                format!("\n{}", self.indent.repeat(self.level))
+            }
+            None => String::new(),
+            Some((_, true)) => " ".to_string(),
+            Some((prev, _)) if "([.".contains(prev) || ")]},;.:".contains(first) && first != '}' => String::new(),
+            Some((prev, _)) if "([".contains(first) && (prev.is_alphanumeric() || prev == '_' || ")]".contains(prev)) => String::new(),
+            Some(_) => " ".to_string(),
+        };
+
+        if !space.is_empty() {
+            out.push(CstChild::Trivia(space));
+        }
+        self.prev = next.chars().last().map(|c| (c, keyword));
+    }
+
+    // The first text of `built`, if it comes before any layout change.
+    fn first_text(built: &Built) -> Option<(&str, bool)> {
+        match built {
+            Built::Node(_, _, children) => children.first().and_then(Layout::first_text),
+            Built::Leaf(_, _, text) => Some((text, false)),
+            Built::Token(text) => Some((text, is_keyword(text))),
+            _ => None,
+        }
+    }
+
+    fn leaf(kind_name: &'static str, kind: Kind, text: String) -> CstNode {
+        CstNode {
+            kind,
+            kind_name,
+            range: None,
+            children: vec![CstChild::Token(text)],
+        }
+    }
+
+    fn finish(&mut self, kind_name: &'static str, kind: Kind, children: Vec<Built>) -> CstNode {
+        let mut out = Vec::new();
+        for child in children {
+            match child {
+                Built::Newline => self.newline = true,
+                Built::Indent => {
+                    self.level += 1;
+                    self.newline = true;
+                }
+                Built::Dedent => self.level = self.level.saturating_sub(1),
+                Built::Token(text) => {
+                    self.space(&text, is_keyword(&text), &mut out);
+                    out.push(CstChild::Token(text));
+                }
+                Built::Leaf(kind_name, kind, text) => {
+                    self.space(&text, false, &mut out);
+                    out.push(CstChild::Node(Layout::leaf(kind_name, kind, text)));
+                }
+                Built::Node(kind_name, kind, children) => {
+                    // Whitespace goes before the outermost node it precedes, as in a parsed tree.
+                    if let Some((text, keyword)) = children.first().and_then(Layout::first_text) {
+                        let text = text.to_string();
+                        self.space(&text, keyword, &mut out);
+                        self.spaced = true;
+                    }
+                    out.push(CstChild::Node(self.finish(kind_name, kind, children)));
+                }
+            }
+        }
+
+        CstNode { kind, kind_name, range: None, children: out }
+    }
+}
+
+// Builds nodes for a language from its grammar, filling in the tokens and whitespace the grammar
+// requires between the children given.
+pub struct TreeBuilder {
+    parser: Parser,
+    rules: HashMap<String, Value>,
+    externals: HashSet<String>,
+    inline: HashSet<String>,
+    has_fields: bool,
+    pub indent: String,
+}
+
+impl TreeBuilder {
+    pub fn new(lang: Language) -> Result<TreeBuilder, Error> {
+        let json = grammar_json(lang).ok_or_else(|| format_err!("{:?} is not compiled in", lang))?;
+        let grammar: Value = serde_json::from_str(json)?;
+
+        let names = |key: &str| grammar[key].as_array().into_iter().flatten()
+            .filter_map(|v| v.as_str().or_else(|| v["name"].as_str()))
+            .map(|s| s.to_string())
+            .collect::<HashSet<_>>();
+
+        let rules = grammar["rules"].as_object()
+            .ok_or_else(|| format_err!("grammar for {:?} has no rules", lang))?
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        Ok(TreeBuilder {
+            parser: Parser::new(lang),
+            rules,
+            externals: names("externals"),
+            inline: names("inline"),
+            has_fields: json.contains("\"FIELD\""),
+            indent: if lang == Language::Python { "    " } else { "  " }.to_string(),
+        })
+    }
+
+    // Builds `node` as if it started on a line indented `level` times, ready to be put into a
+    // `Cst` with `Cst::replace` or `Cst::insert_before`. A newline the grammar requires at the very
+    // end, like the one ending a Python statement, is left to the surrounding text.
+    pub fn build(&self, node: &NodeBuilder, level: usize) -> Result<CstNode, Error> {
+        let mut layout = Layout {
+            indent: &self.indent,
+            level,
+            newline: false,
+            prev: None,
+            spaced: false,
+        };
+
+        Ok(match self.build_node(node)? {
+            Built::Node(kind_name, kind, children) => layout.finish(kind_name, kind, children),
+            Built::Leaf(kind_name, kind, text) => Layout::leaf(kind_name, kind, text),
+            // Anonymous nodes are named after their text.
+            Built::Token(text) => {
+                let kind = self.parser.info.kind_from_name(&text)
+                    .ok_or_else(|| format_err!("unknown token '{}'", text))?;
+                Layout::leaf(self.parser.info.kind_name(kind), kind, text)
+            }
+            Built::Newline | Built::Indent | Built::Dedent => unreachable!(),
+        })
+    }
+
+    fn build_node(&self, node: &NodeBuilder) -> Result<Built, Error> {
+        let kind = self.parser.info.kind_from_name(&node.kind)
+            .ok_or_else(|| format_err!("unknown kind '{}'", node.kind))?;
+        let kind_name = self.parser.info.kind_name(kind);
+
+        if let Some(text) = &node.text {
+            return Ok(Built::Leaf(kind_name, kind, text.clone()));
+        }
+
+        let rule = self.rules.get(&node.kind)
+            .ok_or_else(|| format_err!("no grammar rule for '{}'", node.kind))?;
+
+        let mut matcher = Matcher {
+            builder: self,
+            parts: &node.children,
+            field: None,
+            memo: HashMap::new(),
+            active: HashSet::new(),
+        };
+
+        let (_, _, pieces) = matcher.expand(rule, 0).into_iter()
+            .filter(|s| s.0 == node.children.len())
+            .min_by_key(|s| s.1)
+            .ok_or_else(|| format_err!("can't build '{}' from the given children", node.kind))?;
+
+        let mut children = Vec::new();
+        for piece in pieces {
+            children.push(match piece {
+                Piece::Part(i) => match &node.children[i] {
+                    Part::Node(b) => self.build_node(b)?,
+                    Part::Token(t) => Built::Token(t.clone()),
+                },
+                Piece::Token(t) => Built::Token(t),
+                Piece::Newline => Built::Newline,
+                Piece::Indent => Built::Indent,
+                Piece::Dedent => Built::Dedent,
+            });
+        }
+
+        Ok(Built::Node(kind_name, kind, children))
+    }
+
+    // Serializes `cst`, re-parses it, and checks that the result has no errors and that every
+    // node of `cst` (built or original) comes back with the same kind at the same place.
+    pub fn verify(&mut self, cst: &Cst) -> Result<String, Error> {
+        let text = cst.to_string();
+        let tree = self.parser.parse(&text)?;
+        if tree.root().has_error() {
+            return Err(format_err!("result has syntax errors: {}", text));
+        }
+
+        let mut offset = cst.before.len();
+        check(&cst.root, &tree, &mut offset)?;
+        Ok(text)
+    }
+}
+
+fn check(node: &CstNode, tree: &Tree, offset: &mut usize) -> Result<(), Error> {
+    let start = *offset;
+    for child in &node.children {
+        match child {
+            CstChild::Node(n) => check(n, tree, offset)?,
+            CstChild::Token(t) | CstChild::Trivia(t) => *offset += t.len(),
+        }
+    }
+
+    if *offset == start {
+        return Ok(());
+    }
+
+    let end = *offset;
+    let mut found = Some(tree.root());
+    while let Some(n) = found {
+        if n.start_byte() == start && n.end_byte() == end && n.kind_name() == node.kind_name {
+            return Ok(());
+        }
+        found = n.nodes().find(|c| c.start_byte() <= start && end <= c.end_byte());
+    }
+
+    Err(format_err!("expected '{}' at {:?}", node.kind_name, &tree.text()[start..end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_python() {
+        let text = "def f():\n    return 1\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let f = tree.root().nodes().next().unwrap();
+        let mut cst = Cst::new(&tree);
+        let mut builder = TreeBuilder::new(Language::Python).unwrap();
+
+        let name = |n: &str| NodeBuilder::new("expression_list").child(NodeBuilder::leaf("identifier", n));
+        let g = NodeBuilder::new("function_definition")
+            .child(NodeBuilder::leaf("identifier", "g"))
+            .child(NodeBuilder::new("parameters").child(NodeBuilder::leaf("identifier", "x")))
+            .child(NodeBuilder::new("expression_statement").child(NodeBuilder::new("assignment")
+                .child(name("y"))
+                .child(NodeBuilder::new("expression_list").child(NodeBuilder::new("binary_operator")
+                    .child(NodeBuilder::leaf("identifier", "x"))
+                    .token("+")
+                    .child(NodeBuilder::leaf("integer", "1"))))))
+            .child(NodeBuilder::new("return_statement").child(name("y")));
+
+        assert!(cst.insert_after(&f, CstChild::Node(builder.build(&g, 0).unwrap())));
+        assert!(cst.insert_after(&f, CstChild::Trivia("\n\n".to_string())));
+        assert_eq!(builder.verify(&cst).unwrap(), "def f():\n    return 1\n\ndef g(x):\n    y = x + 1\n    return y\n");
+
+        let bad = NodeBuilder::new("parameters").child(NodeBuilder::new("return_statement"));
+        assert!(builder.build(&bad, 0).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "lang_javascript")]
+    fn test_build_javascript() {
+        let builder = TreeBuilder::new(Language::Javascript).unwrap();
+        let call = NodeBuilder::new("call_expression")
+            .child(NodeBuilder::leaf("identifier", "f"))
+            .child(NodeBuilder::new("arguments")
+                .child(NodeBuilder::leaf("number", "1"))
+                .child(NodeBuilder::leaf("string", "'a'")));
+        assert_eq!(builder.build(&call, 0).unwrap().to_string(), "f(1, 'a')");
+
+        let x = builder.build(&NodeBuilder::leaf("identifier", "x"), 0).unwrap();
+        assert_eq!((x.kind_name, x.to_string()), ("identifier", "x".to_string()));
+        assert!(x.is_leaf());
+    }
+}
@@ -0,0 +1,196 @@
+use crate::parse::{Tree, Range};
+use crate::fingerprint::{HashOptions, FingerprintIndex};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CloneType {
+    // The same code, up to whitespace and comments (type 1).
+    Exact,
+    // The same code up to the names and literals in it (type 2).
+    Renamed,
+}
+
+impl CloneType {
+    pub fn name(self) -> &'static str {
+        match self {
+            CloneType::Exact => "exact",
+            CloneType::Renamed => "renamed",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CloneOptions {
+    // Fragments with fewer tokens than this aren't reported.
+    pub min_tokens: usize,
+    // Also look for renamed clones.
+    pub renamed: bool,
+}
+
+impl Default for CloneOptions {
+    fn default() -> CloneOptions {
+        CloneOptions { min_tokens: 50, renamed: false }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CloneInstance {
+    pub path: String,
+    pub range: Range,
+}
+
+#[derive(Debug, Clone)]
+pub struct CloneGroup {
+    pub kind: CloneType,
+    pub tokens: usize,
+    pub instances: Vec<CloneInstance>,
+}
+
+struct Fragment {
+    file: usize,
+    range: Range,
+    tokens: usize,
+    exact: u64,
+    renamed: u64,
+}
+
+pub struct CloneDetector {
+    options: CloneOptions,
+    paths: Vec<String>,
+    fragments: Vec<Fragment>,
+}
+
+impl CloneDetector {
+    pub fn new(options: CloneOptions) -> CloneDetector {
+        CloneDetector {
+            options,
+            paths: Vec::new(),
+            fragments: Vec::new(),
+        }
+    }
+
+    // Fragments are hashed both exactly and with names and literals left out; comments are
+    // always left out.
+    pub fn add(&mut self, path: &str, tree: &Tree) {
+        self.paths.push(path.to_string());
+        let file = self.paths.len() - 1;
+
+        let exact = HashOptions { ignore_comments: true, ..HashOptions::default() };
+        let renamed = HashOptions { ignore_names: true, ignore_literals: true, ..exact };
+        let exact = FingerprintIndex::new(tree, exact);
+        let renamed = FingerprintIndex::new(tree, renamed);
+
+        for node in tree.nodes().filter(|n| n.is_named()) {
+            if let (Some(e), Some(r)) = (exact.get(&node), renamed.get(&node)) {
+                if e.tokens >= self.options.min_tokens {
+                    self.fragments.push(Fragment { file, range: e.range, tokens: e.tokens, exact: e.hash, renamed: r.hash });
+                }
+            }
+        }
+    }
+
+    // Groups of two or more fragments with the same code, largest first. A group is left out if
+    // all of its fragments are inside the fragments of a larger group.
+    pub fn groups(&self) -> Vec<CloneGroup> {
+        let mut by_hash: HashMap<u64, Vec<&Fragment>> = HashMap::new();
+        for f in &self.fragments {
+            by_hash.entry(if self.options.renamed { f.renamed } else { f.exact }).or_default().push(f);
+        }
+
+        let mut candidates = by_hash.values()
+            .filter(|fragments| fragments.len() > 1)
+            .cloned()
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|fragments| {
+            let first = fragments[0];
+            (std::cmp::Reverse(first.tokens), first.file, first.range.start_byte, std::cmp::Reverse(first.range.end_byte))
+        });
+
+        let mut covered: Vec<&Fragment> = Vec::new();
+        let mut res = Vec::new();
+
+        for fragments in candidates {
+            let inside = |f: &Fragment| covered.iter()
+                .any(|c| c.file == f.file && c.range.start_byte <= f.range.start_byte && f.range.end_byte <= c.range.end_byte);
+            if fragments.iter().all(|f| inside(f)) {
+                continue;
+            }
+
+            let kind = if fragments.iter().all(|f| f.exact == fragments[0].exact) {
+                CloneType::Exact
+            } else {
+                CloneType::Renamed
+            };
+
+            res.push(CloneGroup {
+                kind,
+                tokens: fragments[0].tokens,
+                instances: fragments.iter()
+                    .map(|f| CloneInstance { path: self.paths[f.file].clone(), range: f.range })
+                    .collect(),
+            });
+            covered.extend(fragments);
+        }
+
+        res
+    }
+}
+
+impl CloneGroup {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "type": self.kind.name(),
+            "tokens": self.tokens,
+            "instances": self.instances.iter()
+                .map(|i| json!({
+                    "path": i.path,
+                    "startLine": i.range.start_point.row + 1,
+                    "endLine": i.range.end_point.row + 1,
+                    "startByte": i.range.start_byte,
+                    "endByte": i.range.end_byte,
+                }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{Parser, Language};
+
+    #[test]
+    fn test_python_clones() {
+        let a = "def f(xs):\n    total = 0\n    for x in xs:\n        total += x * 2\n    return total\n";
+        let b = "import os\n\ndef g(ys):\n    # sum\n    acc = 0\n    for y in ys:\n        acc += y * 2\n    return acc\n";
+        let c = "def h(xs):\n    total = 0\n    for x in xs:\n        total += x * 2\n    return total\n";
+
+        let mut parser = Parser::new(Language::Python);
+        let trees = [("a.py", parser.parse(a).unwrap()), ("b.py", parser.parse(b).unwrap()), ("c.py", parser.parse(c).unwrap())];
+
+        let groups = |renamed| {
+            let mut detector = CloneDetector::new(CloneOptions { min_tokens: 10, renamed });
+            for (path, tree) in &trees {
+                detector.add(path, tree);
+            }
+            detector.groups()
+        };
+
+        let exact = groups(false);
+        assert_eq!(exact.len(), 1);
+        assert_eq!(exact[0].kind, CloneType::Exact);
+        assert_eq!(exact[0].instances.iter().map(|i| (i.path.as_str(), i.range.start_point.row)).collect::<Vec<_>>(),
+            vec![("a.py", 2), ("c.py", 2)]);
+
+        // The whole of `a.py` and `c.py` are clones, and so are all three functions.
+        let renamed = groups(true);
+        let instances = renamed.iter()
+            .map(|g| (g.kind, g.instances.iter().map(|i| (i.path.as_str(), i.range.start_point.row)).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(instances, vec![
+            (CloneType::Renamed, vec![("a.py", 0), ("c.py", 0)]),
+            (CloneType::Renamed, vec![("a.py", 0), ("b.py", 2), ("c.py", 0)]),
+        ]);
+    }
+}
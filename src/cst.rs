@@ -0,0 +1,353 @@
+use crate::parse::{Tree, Node, Kind, Child};
+use std::fmt;
+
+// Every leaf of `tree` and all the text between them, in order, starting at the beginning of the
+// source rather than at the root's start and ending at the end of the source. Concatenated, the
+// pieces reproduce the source exactly.
+pub fn leaves<'a>(tree: &'a Tree<'a>) -> Vec<Child<'a>> {
+    let text = tree.text();
+    let mut res = Vec::new();
+    let mut offset = 0;
+    collect_leaves(&tree.root(), text, &mut offset, &mut res);
+    if offset < text.len() {
+        res.push(Child::Text(&text[offset..]));
+    }
+    res
+}
+
+fn collect_leaves<'a>(node: &Node<'a>, text: &'a str, offset: &mut usize, out: &mut Vec<Child<'a>>) {
+    if node.nodes().next().is_some() {
+        for child in node.nodes() {
+            collect_leaves(&child, text, offset, out);
+        }
+        return;
+    }
+
+    if node.start_byte() > *offset {
+        out.push(Child::Text(&text[*offset..node.start_byte()]));
+        *offset = node.start_byte();
+    }
+
+    if node.start_byte() == *offset {
+        out.push(Child::Node(node.clone()));
+        *offset = node.end_byte();
+    } else if node.end_byte() > *offset {
+        // A leaf overlapping the previous one, which only happens around errors; keep whatever
+        // hasn't been covered yet.
+        out.push(Child::Text(&text[*offset..node.end_byte()]));
+        *offset = node.end_byte();
+    }
+}
+
+// An owned, editable copy of a tree that keeps every byte of the source.
+#[derive(Debug, Clone)]
+pub struct Cst {
+    // Text before the root node, e.g. leading whitespace that some grammars leave out.
+    pub before: String,
+    pub root: CstNode,
+    pub after: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct CstNode {
+    pub kind: Kind,
+    pub kind_name: &'static str,
+    // The byte range this node had in the source; `None` for nodes created by edits.
+    pub range: Option<(usize, usize)>,
+    pub children: Vec<CstChild>,
+}
+
+#[derive(Debug, Clone)]
+pub enum CstChild {
+    Node(CstNode),
+    // The text of a leaf, or text inserted by an edit.
+    Token(String),
+    // Whitespace and anything else between leaves.
+    Trivia(String),
+}
+
+impl CstNode {
+    fn build(node: &Node, text: &str, offset: &mut usize) -> CstNode {
+        let mut children = Vec::new();
+
+        if node.nodes().next().is_none() {
+            let start = node.start_byte().max(*offset);
+            if node.end_byte() > start {
+                children.push(CstChild::Token(text[start..node.end_byte()].to_string()));
+                *offset = node.end_byte();
+            }
+        } else {
+            for child in node.nodes() {
+                if child.start_byte() > *offset {
+                    children.push(CstChild::Trivia(text[*offset..child.start_byte()].to_string()));
+                    *offset = child.start_byte();
+                }
+                children.push(CstChild::Node(CstNode::build(&child, text, offset)));
+            }
+        }
+
+        if node.end_byte() > *offset {
+            if !children.is_empty() {
+                children.push(CstChild::Trivia(text[*offset..node.end_byte()].to_string()));
+            }
+            *offset = node.end_byte();
+        }
+
+        CstNode {
+            kind: node.kind(),
+            kind_name: node.kind_name(),
+            range: Some((node.start_byte(), node.end_byte())),
+            children,
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        !self.children.iter().any(|c| matches!(c, CstChild::Node(_)))
+    }
+
+    fn write(&self, out: &mut String) {
+        for child in &self.children {
+            match child {
+                CstChild::Node(n) => n.write(out),
+                CstChild::Token(text) | CstChild::Trivia(text) => out.push_str(text),
+            }
+        }
+    }
+
+    // The child indices leading from this node to the one that was `node` in the source.
+    fn path_to(&self, node: &Node) -> Option<Vec<usize>> {
+        let (start, end) = (node.start_byte(), node.end_byte());
+
+        for (i, child) in self.children.iter().enumerate() {
+            if let CstChild::Node(n) = child {
+                match n.range {
+                    Some((s, e)) if s <= start && end <= e => {
+                        if s == start && e == end && n.kind == node.kind() {
+                            return Some(vec![i]);
+                        }
+                        if let Some(mut path) = n.path_to(node) {
+                            path.insert(0, i);
+                            return Some(path);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl fmt::Display for CstNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::new();
+        self.write(&mut out);
+        write!(f, "{}", out)
+    }
+}
+
+impl Cst {
+    pub fn new(tree: &Tree) -> Cst {
+        let text = tree.text();
+        let root = tree.root();
+        let mut offset = root.start_byte();
+
+        let node = CstNode::build(&root, text, &mut offset);
+        Cst {
+            before: text[..root.start_byte()].to_string(),
+            root: node,
+            after: text[offset..].to_string(),
+        }
+    }
+
+    // The node that was `node` in the source, unless an edit has removed it.
+    pub fn get_mut(&mut self, node: &Node) -> Option<&mut CstNode> {
+        if self.root.range == Some((node.start_byte(), node.end_byte())) && self.root.kind == node.kind() {
+            return Some(&mut self.root);
+        }
+
+        let path = self.root.path_to(node)?;
+        let mut current = &mut self.root;
+        for i in path {
+            current = match &mut current.children[i] {
+                CstChild::Node(n) => n,
+                _ => unreachable!(),
+            };
+        }
+        Some(current)
+    }
+
+    // The parent of the node that was `node` in the source, and its index there.
+    fn locate(&mut self, node: &Node) -> Option<(&mut CstNode, usize)> {
+        let mut path = self.root.path_to(node)?;
+        let index = path.pop()?;
+        let mut parent = &mut self.root;
+        for i in path {
+            parent = match &mut parent.children[i] {
+                CstChild::Node(n) => n,
+                _ => unreachable!(),
+            };
+        }
+        Some((parent, index))
+    }
+
+    // This and the other edits return false if `node` can't be found, e.g. because an earlier
+    // edit replaced something containing it.
+    pub fn replace(&mut self, node: &Node, new: CstChild) -> bool {
+        match self.locate(node) {
+            Some((parent, i)) => {
+                parent.children[i] = new;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn replace_text(&mut self, node: &Node, text: &str) -> bool {
+        self.replace(node, CstChild::Token(text.to_string()))
+    }
+
+    pub fn delete(&mut self, node: &Node) -> bool {
+        match self.locate(node) {
+            Some((parent, i)) => {
+                parent.children.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn insert_before(&mut self, node: &Node, new: CstChild) -> bool {
+        match self.locate(node) {
+            Some((parent, i)) => {
+                parent.children.insert(i, new);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn insert_after(&mut self, node: &Node, new: CstChild) -> bool {
+        match self.locate(node) {
+            Some((parent, i)) => {
+                parent.children.insert(i + 1, new);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for Cst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}{}", self.before, self.root, self.after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{Parser, Language};
+
+    const SAMPLES: &[(Language, &str)] = &[
+        (Language::Javascript, "  // lead\nconst x = {a: [1, 2], b: `t${x}`};\nfunction f(a) { return a /* c */ + 1; }\n\n"),
+        (Language::Python, "\n\nimport os\n\nclass A:\n    def f(self, x):  # c\n        return f\"{x}\" + 'é'\n"),
+        (Language::Rust, "// lead\nfn main() {\n    let x = vec![1, 2];\n}\n"),
+        (Language::C, "#include <stdio.h>\nint main(void) { /* c */ return 0; }\n"),
+        (Language::Cpp, "template <class T> T f(T x) { return x; }\n"),
+        (Language::CSharp, "class A { void F() { var x = 1; } }\n"),
+        (Language::Css, "a > b { color: red; } /* c */\n"),
+        (Language::EmbeddedTemplate, "<p><%= name %></p>\n<% if x %>y<% end %>\n"),
+        (Language::Go, "package main\n\nfunc main() {\n\tx := []int{1, 2}\n}\n"),
+        (Language::Html, "<!DOCTYPE html>\n<p class=\"a\">hi<script>let x = 1;</script></p>\n"),
+        (Language::Scala, "object A { def f(x: Int) = x + 1 }\n"),
+        (Language::Ruby, "def f(x)\n  x + 1 # c\nend\n"),
+        (Language::Bash, "for x in a b; do echo \"$x\"; done\n"),
+        (Language::Typescript, "let x: number = 1;\n"),
+        (Language::Php, "<?php echo 1; ?>\n"),
+        (Language::Ocaml, "let f x = x + 1\n"),
+        (Language::Julia, "function f(x)\n  x + 1\nend\n"),
+        (Language::Agda, "module A where\n"),
+    ];
+
+    // A small xorshift generator, so that failures are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    // Deletes or duplicates random spans of `text`, which mostly produces trees with errors.
+    fn mutate(text: &str, rng: &mut Rng) -> String {
+        let mut res = text.to_string();
+        for _ in 0..3 {
+            let len = res.len();
+            let mut start = rng.next(len + 1);
+            let mut end = (start + rng.next(8)).min(len);
+            while !res.is_char_boundary(start) {
+                start -= 1;
+            }
+            while !res.is_char_boundary(end) {
+                end += 1;
+            }
+
+            if rng.next(2) == 0 {
+                res.replace_range(start..end, "");
+            } else {
+                let span = res[start..end].to_string();
+                res.insert_str(start, &span);
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+        for &(lang, sample) in SAMPLES.iter().filter(|(lang, _)| lang.is_compiled_in()) {
+            let mut parser = Parser::new(lang);
+
+            let mut inputs = vec![sample.to_string()];
+            inputs.extend((0..50).map(|_| mutate(sample, &mut rng)));
+
+            for text in &inputs {
+                let tree = parser.parse(text).unwrap();
+
+                let joined = leaves(&tree).iter()
+                    .map(|c| match c {
+                        Child::Node(n) => n.text(),
+                        Child::Text(t) => t,
+                    })
+                    .collect::<String>();
+                assert_eq!(&joined, text, "{:?} leaves", lang);
+                assert_eq!(&Cst::new(&tree).to_string(), text, "{:?} cst", lang);
+            }
+        }
+    }
+
+    #[test]
+    fn test_edit() {
+        let text = "def f(a, b):\n    return a\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let mut cst = Cst::new(&tree);
+
+        let a = tree.nodes().filter(|n| n.text() == "a").last().unwrap();
+        let params = tree.nodes().find(|n| n.kind_name() == "parameters").unwrap();
+        let ret = tree.nodes().find(|n| n.kind_name() == "return_statement").unwrap();
+
+        assert!(cst.replace_text(&a, "b"));
+        assert!(cst.replace_text(&params, "(b)"));
+        assert!(cst.insert_before(&ret, CstChild::Token("pass\n    ".to_string())));
+        assert!(!cst.delete(&a));
+        assert_eq!(cst.to_string(), "def f(b):\n    pass\n    return b\n");
+
+        assert!(!cst.get_mut(&ret).unwrap().is_leaf());
+    }
+}
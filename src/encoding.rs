@@ -0,0 +1,304 @@
+use crate::parse::{Language, Range};
+use failure::{Error, format_err};
+use regex::bytes::Regex;
+use std::str::FromStr;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+    Windows1252,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Latin1 => "iso-8859-1",
+            Encoding::Windows1252 => "windows-1252",
+        }
+    }
+}
+
+// Accepts the common spellings of each name, including Python's (`latin_1`, `utf8`, ...).
+impl FromStr for Encoding {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Error> {
+        Ok(match text.to_lowercase().replace('_', "-").as_str() {
+            "utf-8" | "utf8" | "u8" | "ascii" | "us-ascii" => Encoding::Utf8,
+            "utf-16le" | "utf-16-le" => Encoding::Utf16Le,
+            "utf-16be" | "utf-16-be" => Encoding::Utf16Be,
+            "iso-8859-1" | "iso8859-1" | "latin-1" | "latin1" | "l1" => Encoding::Latin1,
+            "windows-1252" | "cp1252" => Encoding::Windows1252,
+            _ => return Err(format_err!("invalid encoding '{}'", text))
+        })
+    }
+}
+
+// What windows-1252 has in place of the C1 controls at 0x80..0xa0. The five unassigned bytes
+// decode to the controls, as browsers do.
+const WINDOWS_1252: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+// A file's contents as UTF-8, and how to get back to the original bytes.
+#[derive(Debug, Clone)]
+pub struct Decoded {
+    pub text: String,
+    pub encoding: Encoding,
+    // Whether the original started with a byte order mark.
+    pub bom: bool,
+    // Whether bytes that aren't valid in `encoding` were replaced with U+FFFD.
+    pub lossy: bool,
+    pub warning: Option<String>,
+    // (offset in `text`, offset in the original) at the start of each stretch where the two
+    // advance together.
+    runs: Vec<(usize, usize)>,
+}
+
+impl Decoded {
+    fn new(encoding: Encoding, bom: usize) -> Decoded {
+        Decoded {
+            text: String::new(),
+            encoding,
+            bom: bom > 0,
+            lossy: false,
+            warning: None,
+            runs: vec![(0, bom)],
+        }
+    }
+
+    // Appends `text`, which started at byte `original` of the original.
+    fn push(&mut self, text: &str, original: usize) {
+        let (t, o) = self.runs[self.runs.len() - 1];
+        if self.text.len() - t != original - o {
+            self.runs.push((self.text.len(), original));
+        }
+        self.text.push_str(text);
+    }
+
+    fn push_char(&mut self, c: char, original: usize) {
+        self.push(c.encode_utf8(&mut [0; 4]), original);
+    }
+
+    fn finish(mut self, end: usize) -> Decoded {
+        self.push("", end);
+        self
+    }
+
+    // Where a byte offset in `text` was in the original bytes.
+    pub fn original_offset(&self, offset: usize) -> usize {
+        let i = match self.runs.binary_search_by_key(&offset, |r| r.0) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        let (t, o) = self.runs[i];
+        o + offset - t
+    }
+
+    // `range` with its offsets and columns counting original bytes. Rows don't change.
+    pub fn original_range(&self, range: Range) -> Range {
+        let mut res = range;
+        res.start_byte = self.original_offset(range.start_byte);
+        res.end_byte = self.original_offset(range.end_byte);
+        res.start_point.column = res.start_byte - self.original_offset(range.start_byte - range.start_point.column);
+        res.end_point.column = res.end_byte - self.original_offset(range.end_byte - range.end_point.column);
+        res
+    }
+
+    // `text` in the original encoding, with the original byte order mark if there was one.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, Error> {
+        let mut res = Vec::new();
+        match self.encoding {
+            Encoding::Utf8 => {
+                if self.bom {
+                    res.extend_from_slice(b"\xef\xbb\xbf");
+                }
+                res.extend_from_slice(text.as_bytes());
+            }
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                let le = self.encoding == Encoding::Utf16Le;
+                for unit in (if self.bom { Some(0xfeff) } else { None }).into_iter().chain(text.encode_utf16()) {
+                    res.extend_from_slice(&if le { unit.to_le_bytes() } else { unit.to_be_bytes() });
+                }
+            }
+            Encoding::Latin1 | Encoding::Windows1252 => {
+                for c in text.chars() {
+                    let code = c as u32;
+                    let byte = if code < 0x80 || (0xa0..0x100).contains(&code) ||
+                        (code < 0xa0 && self.encoding == Encoding::Latin1) {
+                        Some(code as u8)
+                    } else {
+                        WINDOWS_1252.iter().position(|&w| w == c).filter(|_| self.encoding == Encoding::Windows1252).map(|i| 0x80 + i as u8)
+                    };
+                    res.push(byte.ok_or_else(|| format_err!("{:?} can't be encoded in {}", c, self.encoding.name()))?);
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+// The encoding a file says it's in: a coding comment on one of the first two lines of Python
+// and Ruby, or a <meta> charset near the start of HTML.
+pub fn declared_encoding(bytes: &[u8], lang: Option<Language>) -> Option<String> {
+    let (re, head) = match lang? {
+        Language::Python | Language::Ruby => {
+            let end = bytes.iter().enumerate().filter(|&(_, &b)| b == b'\n').nth(1).map_or(bytes.len(), |(i, _)| i);
+            (r"(?m)^[ \t\x0c]*#.*?coding[:=][ \t]*([-\w.]+)", &bytes[..end])
+        }
+        Language::Html | Language::Php | Language::EmbeddedTemplate => {
+            (r#"(?i)<meta[^>]*?charset\s*=\s*["']?([-\w.:]+)"#, &bytes[..bytes.len().min(1024)])
+        }
+        _ => return None,
+    };
+
+    let captures = Regex::new(re).unwrap().captures(head)?;
+    Some(String::from_utf8_lossy(&captures[1]).into_owned())
+}
+
+fn decode_utf8(bytes: &[u8], bom: usize) -> Decoded {
+    let mut res = Decoded::new(Encoding::Utf8, bom);
+    let mut offset = bom;
+    loop {
+        match std::str::from_utf8(&bytes[offset..]) {
+            Ok(text) => {
+                res.push(text, offset);
+                return res.finish(bytes.len());
+            }
+            Err(e) => {
+                let valid = e.valid_up_to();
+                res.push(std::str::from_utf8(&bytes[offset..offset + valid]).unwrap(), offset);
+                offset += valid;
+                res.push_char('\u{fffd}', offset);
+                res.lossy = true;
+                offset += e.error_len().unwrap_or(bytes.len() - offset);
+            }
+        }
+    }
+}
+
+fn decode_utf16(bytes: &[u8], bom: usize, encoding: Encoding) -> Decoded {
+    let mut res = Decoded::new(encoding, bom);
+    let units = bytes[bom..].chunks(2).filter(|c| c.len() == 2).map(|c| match encoding {
+        Encoding::Utf16Le => u16::from_le_bytes([c[0], c[1]]),
+        _ => u16::from_be_bytes([c[0], c[1]]),
+    });
+
+    let mut offset = bom;
+    for c in std::char::decode_utf16(units) {
+        match c {
+            Ok(c) => {
+                res.push_char(c, offset);
+                offset += 2 * c.len_utf16();
+            }
+            Err(_) => {
+                res.push_char('\u{fffd}', offset);
+                res.lossy = true;
+                offset += 2;
+            }
+        }
+    }
+    if offset < bytes.len() {
+        res.push_char('\u{fffd}', offset);
+        res.lossy = true;
+    }
+    res.finish(bytes.len())
+}
+
+fn decode_single_byte(bytes: &[u8], encoding: Encoding) -> Decoded {
+    let mut res = Decoded::new(encoding, 0);
+    for (i, &b) in bytes.iter().enumerate() {
+        let c = match b {
+            0x80..=0x9f if encoding == Encoding::Windows1252 => WINDOWS_1252[b as usize - 0x80],
+            _ => b as char,
+        };
+        res.push_char(c, i);
+    }
+    res.finish(bytes.len())
+}
+
+// Decodes a source file: a byte order mark wins, then a declared encoding, then UTF-8. Bytes
+// that don't decode are replaced rather than failing, and `warning` says so.
+pub fn decode(bytes: &[u8], lang: Option<Language>) -> Decoded {
+    let mut warning = None;
+    let (encoding, bom) = if bytes.starts_with(b"\xef\xbb\xbf") {
+        (Encoding::Utf8, 3)
+    } else if bytes.starts_with(b"\xff\xfe") {
+        (Encoding::Utf16Le, 2)
+    } else if bytes.starts_with(b"\xfe\xff") {
+        (Encoding::Utf16Be, 2)
+    } else {
+        match declared_encoding(bytes, lang) {
+            Some(name) => match name.parse() {
+                Ok(encoding) => (encoding, 0),
+                Err(_) => {
+                    warning = Some(format!("unsupported encoding '{}', reading as utf-8", name));
+                    (Encoding::Utf8, 0)
+                }
+            },
+            None => (Encoding::Utf8, 0),
+        }
+    };
+
+    let mut res = match encoding {
+        Encoding::Utf8 => decode_utf8(bytes, bom),
+        Encoding::Utf16Le | Encoding::Utf16Be => decode_utf16(bytes, bom, encoding),
+        Encoding::Latin1 | Encoding::Windows1252 => decode_single_byte(bytes, encoding),
+    };
+    if res.lossy && warning.is_none() {
+        warning = Some(format!("invalid {}, replaced with U+FFFD", encoding.name()));
+    }
+    res.warning = warning;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::Parser;
+
+    #[test]
+    fn test_decode() {
+        let latin1 = b"# -*- coding: latin-1 -*-\ns = '\xe9t\xe9'\n";
+        let decoded = decode(latin1, Some(Language::Python));
+        assert_eq!(decoded.encoding, Encoding::Latin1);
+        assert_eq!(decoded.text, "# -*- coding: latin-1 -*-\ns = 'été'\n");
+        assert_eq!(decoded.warning, None);
+        assert_eq!(decoded.encode(&decoded.text).unwrap(), latin1.to_vec());
+
+        let tree = Parser::new(Language::Python).parse(&decoded.text).unwrap();
+        let string = tree.nodes().find(|n| n.kind_name() == "string").unwrap();
+        let range = decoded.original_range(string.range());
+        assert_eq!((range.start_byte, range.end_byte), (30, 35));
+        assert_eq!((range.start_point.column, range.end_point.column), (4, 9));
+        assert_eq!(decoded.original_offset(decoded.text.len()), latin1.len());
+
+        let mut utf16 = vec![0xff, 0xfe];
+        utf16.extend("x = '😀'\n".encode_utf16().flat_map(|u| u.to_le_bytes().to_vec()));
+        let decoded = decode(&utf16, None);
+        assert_eq!((decoded.encoding, decoded.bom, decoded.text.as_str()), (Encoding::Utf16Le, true, "x = '😀'\n"));
+        assert_eq!(decoded.original_offset(9), 2 + 2 * 7);
+        assert_eq!(decoded.encode(&decoded.text).unwrap(), utf16);
+
+        let html = b"<html><head><meta charset=\"windows-1252\"></head><p>\x93hi\x94</p>";
+        assert_eq!(decode(html, Some(Language::Html)).text, "<html><head><meta charset=\"windows-1252\"></head><p>\u{201c}hi\u{201d}</p>");
+
+        // No declaration, so invalid UTF-8 is replaced.
+        let decoded = decode(b"a = '\xe9'\nb = 1\n", Some(Language::Python));
+        assert!(decoded.lossy && decoded.warning.is_some());
+        assert_eq!(decoded.text, "a = '\u{fffd}'\nb = 1\n");
+        assert_eq!(decoded.original_offset(10), 8);
+
+        let decoded = decode(b"# coding: shift_jis\n", Some(Language::Python));
+        assert_eq!(decoded.warning.unwrap(), "unsupported encoding 'shift_jis', reading as utf-8");
+    }
+}
@@ -0,0 +1,481 @@
+use crate::parse::{Parser, Node, Kind, Child};
+use failure::{Error, format_err};
+use regex::Regex;
+use std::str::FromStr;
+
+pub(crate) fn find_example<'a>(node: Node<'a>, ex: &str) -> Option<Node<'a>> {
+    if node.text().contains(ex) {
+        for ch in node.children() {
+            match ch {
+                Child::Node(ch) => {
+                    if let Some(n) = find_example(ch, ex) {
+                        return Some(n);
+                    }
+                }
+                Child::Text(_) => {}
+            }
+        }
+        Some(node.clone())
+    } else {
+        None
+    }
+}
+
+// Placeholders in examples are replaced by identifiers before parsing, since most languages
+// don't allow `$` in an identifier.
+const VAR_PREFIX: &str = "hornbeam_var_";
+const REST: &str = "hornbeam_rest";
+
+// Patterns starting with this are kind names rather than examples, e.g. `kind:if_statement`.
+const KIND_PREFIX: &str = "kind:";
+
+// Kinds of attributes that come before the item they apply to as its siblings, rather than
+// inside it, like Rust's `#[cfg(test)]`.
+const ATTRIBUTE_KINDS: &[&str] = &["attribute_item"];
+
+fn is_attribute(node: &Node) -> bool {
+    ATTRIBUTE_KINDS.contains(&node.kind_name())
+}
+
+fn is_comment(node: &Node) -> bool {
+    node.kind_name().contains("comment")
+}
+
+// The attributes directly before `node`, in order.
+fn attributes<'a>(node: &Node<'a>) -> Vec<Node<'a>> {
+    let mut res = Vec::new();
+    let mut prev = node.prev_sibling();
+    while let Some(p) = prev {
+        if is_attribute(&p) {
+            res.push(p.clone());
+        } else if !is_comment(&p) {
+            break;
+        }
+        prev = p.prev_sibling();
+    }
+    res.reverse();
+    res
+}
+
+pub fn substitute_placeholders(example: &str) -> String {
+    let mut res = String::new();
+    let mut chars = example.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            res.push(c);
+        } else if chars.clone().take(3).collect::<String>() == "..." {
+            chars.nth(2);
+            res.push_str(REST);
+        } else if matches!(chars.peek(), Some(c) if c.is_ascii_uppercase() || *c == '_') {
+            res.push_str(VAR_PREFIX);
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_') {
+                    break;
+                }
+                res.push(c);
+                chars.next();
+            }
+        } else {
+            res.push(c);
+        }
+    }
+
+    res
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TextMode {
+    Exact,
+    IgnoreCase,
+    // The example's leaf text is a regex that must match the whole leaf.
+    Regex,
+}
+
+impl FromStr for TextMode {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Error> {
+        Ok(match text {
+            "exact" => TextMode::Exact,
+            "ignore-case" => TextMode::IgnoreCase,
+            "regex" => TextMode::Regex,
+            _ => return Err(format_err!("invalid text match mode '{}'", text))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum TextMatch {
+    Exact(String),
+    IgnoreCase(String),
+    Regex(Regex),
+}
+
+impl TextMatch {
+    pub fn new(mode: TextMode, text: &str) -> Result<TextMatch, Error> {
+        Ok(match mode {
+            TextMode::Exact => TextMatch::Exact(text.to_string()),
+            TextMode::IgnoreCase => TextMatch::IgnoreCase(text.to_lowercase()),
+            TextMode::Regex => TextMatch::Regex(Regex::new(&format!("^(?:{})$", text))?),
+        })
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        match self {
+            TextMatch::Exact(t) => t == text,
+            TextMatch::IgnoreCase(t) => *t == text.to_lowercase(),
+            TextMatch::Regex(r) => r.is_match(text),
+        }
+    }
+}
+
+// Which leaves of an example have to match by text, rather than just by kind. `*` stands for
+// every kind.
+pub struct Literals {
+    pub kinds: Vec<String>,
+    pub mode: TextMode,
+}
+
+impl Literals {
+    fn text_match(&self, node: &Node) -> Result<Option<TextMatch>, Error> {
+        let is_leaf = node.nodes().next().is_none();
+        if is_leaf && self.kinds.iter().any(|k| k == "*" || k == node.kind_name()) {
+            Ok(Some(TextMatch::new(self.mode, node.text())?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// Metavariable names and the text each is bound to, in the order they were bound.
+pub type Bindings = Vec<(String, String)>;
+
+#[derive(Debug)]
+pub enum Schema {
+    Node {
+        kind: Kind,
+        named: bool,
+        text: Option<TextMatch>,
+        children: Vec<Schema>,
+    },
+    // `$X`: any single node, which must have the same text wherever `$X` appears.
+    Var(String),
+    // `$...`: any sequence of nodes, including none.
+    Rest,
+    // An item and attributes that have to be among those on it, for examples like
+    // `#[cfg(test)] mod $M { $... }` where the attributes are the item's siblings.
+    Attributed(Vec<Schema>, Box<Schema>),
+}
+
+impl Schema {
+    pub fn from(node: Node, literals: &Literals) -> Result<Schema, Error> {
+        let text = node.text();
+        Ok(if text == REST {
+            Schema::Rest
+        } else if text.starts_with(VAR_PREFIX) && text[VAR_PREFIX.len()..].chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_') {
+            Schema::Var(text[VAR_PREFIX.len()..].to_string())
+        } else {
+            Schema::Node {
+                kind: node.kind(),
+                named: node.is_named(),
+                text: literals.text_match(&node)?,
+                children: node.nodes().map(|n| Schema::from(n, literals)).collect::<Result<_, _>>()?,
+            }
+        })
+    }
+
+    pub fn matches(&self, node: &Node, bindings: &mut Bindings) -> bool {
+        match self {
+            Schema::Node { kind, text, children, .. } => {
+                if *kind != node.kind() || matches!(text, Some(t) if !t.matches(node.text())) {
+                    return false;
+                }
+
+                let nodes = node.nodes().collect::<Vec<_>>();
+                Schema::matches_seq(children, &nodes, bindings)
+            }
+            Schema::Var(name) => {
+                if let Some((_, text)) = bindings.iter().find(|(n, _)| n == name) {
+                    return text == node.text();
+                }
+                bindings.push((name.clone(), node.text().to_string()));
+                true
+            }
+            Schema::Rest => true,
+            Schema::Attributed(attrs, item) => {
+                let actual = attributes(node);
+                let mut actual = actual.iter();
+                item.matches(node, bindings) &&
+                    attrs.iter().all(|a| actual.any(|n| Schema::try_bind(bindings, |b| a.matches(n, b))))
+            }
+        }
+    }
+
+    fn matches_seq(schemas: &[Schema], nodes: &[Node], bindings: &mut Bindings) -> bool {
+        match schemas.split_first() {
+            None => nodes.is_empty(),
+            Some((Schema::Rest, rest)) => {
+                (0..=nodes.len()).any(|skip| Schema::try_bind(bindings, |b| Schema::matches_seq(rest, &nodes[skip..], b)))
+            }
+            // A separator before `$...`, like the comma in `f(a, $...)`, goes away if the
+            // sequence is empty.
+            Some((Schema::Node { named: false, .. }, rest)) if matches!(rest.first(), Some(Schema::Rest)) &&
+                Schema::try_bind(bindings, |b| Schema::matches_seq(&rest[1..], nodes, b)) => true,
+            Some((schema, rest)) => match nodes.split_first() {
+                Some((node, nodes)) => Schema::try_bind(bindings, |b| schema.matches(node, b) && Schema::matches_seq(rest, nodes, b)),
+                None => false,
+            },
+        }
+    }
+
+    // Keeps the bindings made by `f` only if it succeeds.
+    pub(crate) fn try_bind(bindings: &mut Bindings, f: impl FnOnce(&mut Bindings) -> bool) -> bool {
+        let mut attempt = bindings.clone();
+        if f(&mut attempt) {
+            *bindings = attempt;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Finder {
+    Kind(Kind),
+    Schema(Schema),
+    // Nodes matching the first finder that have an ancestor matching the second.
+    Inside(Box<Finder>, Box<Finder>),
+    // Nodes matching the first finder that have no ancestor matching the second.
+    NotInside(Box<Finder>, Box<Finder>),
+    // Nodes matching the first finder that have a descendant matching the second.
+    Has(Box<Finder>, Box<Finder>),
+}
+
+impl Schema {
+    // A schema from an example snippet, which may contain `$X` and `$...` placeholders. If the
+    // snippet doesn't parse on its own, `context` can surround it, with `@@` marking its place.
+    pub fn from_example(parser: &mut Parser, example: &str, context: Option<&str>, literals: &Literals) -> Result<Schema, Error> {
+        let example = substitute_placeholders(example);
+        let full = match context {
+            Some(context) => substitute_placeholders(context).replace("@@", &example),
+            None => example.clone(),
+        };
+
+        let tree = parser.parse(&full)?;
+        let node = find_example(tree.root(), &example)
+            .ok_or_else(|| format_err!("example {:?} not found in {:?}", example, full))?;
+
+        // The example covers an item and its attributes, so finding it found their parent.
+        let parts = node.nodes().filter(|n| !is_comment(n)).collect::<Vec<_>>();
+        if let Some((item, attrs)) = parts.split_last() {
+            if !attrs.is_empty() && !is_attribute(item) && attrs.iter().all(is_attribute) {
+                let attrs = attrs.iter().map(|a| Schema::from(a.clone(), literals)).collect::<Result<_, _>>()?;
+                return Ok(Schema::Attributed(attrs, Box::new(Schema::from(item.clone(), literals)?)));
+            }
+        }
+
+        Schema::from(node, literals)
+    }
+}
+
+fn descendants<'a>(node: &Node<'a>) -> Vec<Node<'a>> {
+    let mut res = Vec::new();
+    let mut stack = node.nodes().collect::<Vec<_>>();
+    while let Some(n) = stack.pop() {
+        stack.extend(n.nodes());
+        res.push(n);
+    }
+    res
+}
+
+impl Finder {
+    // A finder for `kind:` and a kind name, or else for an example as in `Schema::from_example`.
+    // Kind names need the prefix, since plenty of them (`None`, `true`, `break`) are examples too.
+    pub fn from_pattern(parser: &mut Parser, pattern: &str, context: Option<&str>, literals: &Literals) -> Result<Finder, Error> {
+        if let Some(name) = pattern.strip_prefix(KIND_PREFIX) {
+            let name = name.trim();
+            let kind = parser.info.kind_from_name(name)
+                .ok_or_else(|| format_err!("{:?} has no kind named '{}'", parser.language(), name))?;
+            return Ok(Finder::Kind(kind));
+        }
+        Schema::from_example(parser, pattern, context, literals).map(Finder::Schema)
+    }
+
+    pub fn inside(self, ancestor: Finder) -> Finder {
+        Finder::Inside(Box::new(self), Box::new(ancestor))
+    }
+
+    pub fn not_inside(self, ancestor: Finder) -> Finder {
+        Finder::NotInside(Box::new(self), Box::new(ancestor))
+    }
+
+    pub fn has(self, descendant: Finder) -> Finder {
+        Finder::Has(Box::new(self), Box::new(descendant))
+    }
+
+    // The metavariable bindings if `node` matches. Metavariables shared between a finder and its
+    // constraints must bind the same text.
+    pub fn matches(&self, node: &Node) -> Option<Bindings> {
+        let mut bindings = Vec::new();
+        if self.matches_into(node, &mut bindings) {
+            Some(bindings)
+        } else {
+            None
+        }
+    }
+
+    fn matches_into(&self, node: &Node, bindings: &mut Bindings) -> bool {
+        match self {
+            Finder::Kind(k) => node.kind() == *k,
+            Finder::Schema(s) => s.matches(node, bindings),
+            Finder::Inside(f, ancestor) => {
+                f.matches_into(node, bindings) && {
+                    let mut parent = node.parent();
+                    let mut found = false;
+                    while let Some(p) = parent {
+                        if Schema::try_bind(bindings, |b| ancestor.matches_into(&p, b)) {
+                            found = true;
+                            break;
+                        }
+                        parent = p.parent();
+                    }
+                    found
+                }
+            }
+            Finder::NotInside(f, ancestor) => {
+                f.matches_into(node, bindings) && {
+                    let mut parent = node.parent();
+                    let mut found = false;
+                    while let Some(p) = parent {
+                        if ancestor.matches_into(&p, &mut bindings.clone()) {
+                            found = true;
+                            break;
+                        }
+                        parent = p.parent();
+                    }
+                    !found
+                }
+            }
+            Finder::Has(f, descendant) => {
+                f.matches_into(node, bindings) &&
+                    descendants(node).iter().any(|d| Schema::try_bind(bindings, |b| descendant.matches_into(d, b)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::Language;
+
+    // The text of each node of `text` that `example` matches, with its bindings.
+    fn find(text: &str, example: &str, literals: &Literals) -> Vec<(String, Bindings)> {
+        let mut parser = Parser::new(Language::Python);
+        let finder = Schema::from_example(&mut parser, example, None, literals).map(Finder::Schema).unwrap();
+        let tree = parser.parse(text).unwrap();
+        tree.nodes()
+            .filter_map(|n| finder.matches(&n).map(|b| (n.text().to_string(), b)))
+            .collect()
+    }
+
+    fn texts(found: Vec<(String, Bindings)>) -> Vec<String> {
+        found.into_iter().map(|(text, _)| text).collect()
+    }
+
+    #[test]
+    fn test_placeholders() {
+        assert_eq!(substitute_placeholders("f($X, $...) + $y"), "f(hornbeam_var_X, hornbeam_rest) + $y");
+
+        let literals = Literals { kinds: vec!["identifier".to_string(), "integer".to_string()], mode: TextMode::Exact };
+        let bind = |name: &str, text: &str| (name.to_string(), text.to_string());
+
+        let found = find("f(a, a)\nf(a, b)\nf(b.c, b.c)\ng(a, a)\n", "f($X, $X)", &literals);
+        assert_eq!(found, vec![
+            ("f(a, a)".to_string(), vec![bind("X", "a")]),
+            ("f(b.c, b.c)".to_string(), vec![bind("X", "b.c")]),
+        ]);
+
+        // The comma before `$...` isn't needed when the rest is empty.
+        let text = "f(1)\nf(1, 2)\nf(1, 2, 3)\nf(2, 1)\nf()\n";
+        assert_eq!(texts(find(text, "f(1, $...)", &literals)), vec!["f(1)", "f(1, 2)", "f(1, 2, 3)"]);
+        assert_eq!(texts(find(text, "f($...)", &literals)), vec!["f(1)", "f(1, 2)", "f(1, 2, 3)", "f(2, 1)", "f()"]);
+        assert_eq!(texts(find(text, "f($X, $...)", &literals)).len(), 4);
+
+        let found = find("a.b(1, x)\n", "$OBJ.$METHOD($...)", &literals);
+        assert_eq!(found, vec![("a.b(1, x)".to_string(), vec![bind("OBJ", "a"), bind("METHOD", "b")])]);
+    }
+
+    #[test]
+    fn test_literals() {
+        let literals = |kinds: &[&str], mode| Literals { kinds: kinds.iter().map(|k| k.to_string()).collect(), mode };
+        let text = "x.y()\nfoo.unwrap()\nFoo.Unwrap()\nfoo.expect()\n";
+
+        assert_eq!(find(text, "foo.unwrap()", &literals(&[], TextMode::Exact)).len(), 4);
+        assert_eq!(texts(find(text, "foo.unwrap()", &literals(&["identifier"], TextMode::Exact))), vec!["foo.unwrap()"]);
+        assert_eq!(texts(find(text, "foo.unwrap()", &literals(&["identifier"], TextMode::IgnoreCase))), vec!["foo.unwrap()", "Foo.Unwrap()"]);
+
+        // `*` makes every leaf literal, operators included.
+        let text = "x = 1\nx += 1\nx = 2\n";
+        assert_eq!(texts(find(text, "x = 1", &literals(&["identifier"], TextMode::Exact))), vec!["x = 1", "x = 2"]);
+        assert_eq!(texts(find(text, "x = 1", &literals(&["*"], TextMode::Exact))), vec!["x = 1"]);
+
+        // A regex has to match the whole leaf.
+        let text = "f(  # TODO\n)\nf(  # TOODO: later\n)\nf(  # not TODO\n)\n";
+        let regex = literals(&["comment"], TextMode::Regex);
+        assert_eq!(texts(find(text, "f(  # TO+DO.*\n)", &regex)), vec!["f(  # TODO\n)", "f(  # TOODO: later\n)"]);
+        assert!(Schema::from_example(&mut Parser::new(Language::Python), "f(  # (\n)", None, &regex).is_err());
+        assert!("regex".parse::<TextMode>().is_ok() && "glob".parse::<TextMode>().is_err());
+    }
+
+    #[test]
+    fn test_relational() {
+        let text = "def main():\n    g(1)\n    if x:\n        g(2)\n\ndef other():\n    g(3)\n";
+        let mut parser = Parser::new(Language::Python);
+        let tree = parser.parse(text).unwrap();
+        let literals = Literals { kinds: vec!["identifier".to_string()], mode: TextMode::Exact };
+
+        let call = || Schema::from_example(&mut Parser::new(Language::Python), "g($X)", None, &literals).map(Finder::Schema).unwrap();
+        let main = Schema::from_example(&mut parser, "def main():\n    $...", None, &literals).map(Finder::Schema).unwrap();
+        let if_stmt = Finder::Kind(parser.info.kind_from_name("if_statement").unwrap());
+        let found = |finder: &Finder| tree.nodes()
+            .filter_map(|n| finder.matches(&n))
+            .map(|b| b[0].1.clone())
+            .collect::<Vec<_>>();
+
+        assert_eq!(found(&call()), vec!["1", "2", "3"]);
+        let main_calls = call().inside(main);
+        assert_eq!(found(&main_calls), vec!["1", "2"]);
+        assert_eq!(found(&main_calls.not_inside(if_stmt)), vec!["1"]);
+
+        let has_call = Finder::Kind(parser.info.kind_from_name("function_definition").unwrap()).has(call());
+        assert_eq!(tree.nodes().filter(|n| has_call.matches(n).is_some()).count(), 2);
+
+        // Kind names need their prefix, so that `None` is an example.
+        assert!(matches!(Finder::from_pattern(&mut parser, "kind:if_statement", None, &literals), Ok(Finder::Kind(_))));
+        assert!(matches!(Finder::from_pattern(&mut parser, "None", None, &literals), Ok(Finder::Schema(_))));
+        assert!(Finder::from_pattern(&mut parser, "kind:no_such_kind", None, &literals).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "lang_rust")]
+    fn test_attributes() {
+        let text = "fn f() { g(1); }\n\n#[cfg(test)]\nmod tests {\n    fn t() { g(2); }\n}\n\n#[cfg(feature = \"x\")]\n#[cfg(test)]\nmod more {\n    fn u() { g(3); }\n}\n\nmod other {\n    fn v() { g(4); }\n}\n";
+        let tree = Parser::new(Language::Rust).parse(text).unwrap();
+        let literals = Literals { kinds: vec!["identifier".to_string()], mode: TextMode::Exact };
+
+        let finder = |pattern: &str| Finder::from_pattern(&mut Parser::new(Language::Rust), pattern, None, &literals).unwrap();
+        let found = |finder: &Finder| tree.nodes()
+            .filter_map(|n| finder.matches(&n))
+            .map(|b| b[0].1.clone())
+            .collect::<Vec<_>>();
+
+        // The attributes are siblings of the module, not its ancestors, but still count as part of it.
+        let test_mod = "#[cfg(test)] mod $M { $... }";
+        assert_eq!(found(&finder("g($X);").inside(finder(test_mod))), vec!["2", "3"]);
+        assert_eq!(found(&finder("g($X);").not_inside(finder(test_mod))), vec!["1", "4"]);
+        assert_eq!(found(&finder(test_mod)), vec!["tests", "more"]);
+    }
+}
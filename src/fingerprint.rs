@@ -0,0 +1,236 @@
+use crate::parse::{Tree, Node, Kind, Child, Range};
+use crate::symbols;
+use std::collections::HashMap;
+
+// What a structural hash leaves out, so that code that differs only in those ways hashes the same.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct HashOptions {
+    pub ignore_whitespace: bool,
+    pub ignore_comments: bool,
+    pub ignore_names: bool,
+    pub ignore_literals: bool,
+}
+
+impl Default for HashOptions {
+    fn default() -> HashOptions {
+        HashOptions {
+            ignore_whitespace: true,
+            ignore_comments: false,
+            ignore_names: false,
+            ignore_literals: false,
+        }
+    }
+}
+
+// 64-bit FNV-1a. Unlike `DefaultHasher`, its output is fixed, so hashes can be stored and
+// compared across runs and versions.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= u64::from(*b);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    // Length-prefixed, so that "ab" + "c" and "a" + "bc" differ.
+    fn write_str(&mut self, text: &str) {
+        self.write_u64(text.len() as u64);
+        self.write(text.as_bytes());
+    }
+}
+
+fn is_comment(node: &Node) -> bool {
+    node.kind_name().contains("comment")
+}
+
+fn is_literal(node: &Node) -> bool {
+    let kind = node.kind_name();
+    node.is_named() && !symbols::is_name(node) && !is_comment(node) &&
+        (node.nodes().next().is_none() || kind.contains("string") || kind.contains("literal"))
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Fingerprint {
+    pub hash: u64,
+    pub kind: Kind,
+    pub range: Range,
+    // The number of leaves that went into the hash.
+    pub tokens: usize,
+}
+
+// A node whose hash is partway done: its kind and the children before `children` are in.
+struct Frame<'a> {
+    node: Node<'a>,
+    hash: Fnv,
+    tokens: usize,
+    children: std::vec::IntoIter<Child<'a>>,
+}
+
+impl<'a> Frame<'a> {
+    fn new(node: Node<'a>, options: HashOptions) -> Frame<'a> {
+        let mut hash = Fnv::new();
+        hash.write_str(node.kind_name());
+        let mut tokens = 0;
+        let mut children = Vec::new();
+
+        if options.ignore_literals && is_literal(&node) {
+            tokens = 1;
+        } else if node.nodes().next().is_none() {
+            if !(options.ignore_names && symbols::is_name(&node)) {
+                hash.write_str(node.text());
+            }
+            tokens = 1;
+        } else {
+            children = node.children().collect();
+        }
+
+        Frame { node, hash, tokens, children: children.into_iter() }
+    }
+}
+
+// Hashes `node`, adding the fingerprint of it and every node under it to `out` in post-order.
+// Kinds are hashed by name rather than by id, so hashes don't change when a grammar is
+// regenerated and can match between languages whose grammars share names. The walk keeps its
+// own stack, since generated code can nest deeper than the thread's stack would allow.
+fn hash_node(node: &Node, options: HashOptions, out: &mut Option<&mut Vec<Fingerprint>>) -> (u64, usize) {
+    let mut stack = vec![Frame::new(node.clone(), options)];
+
+    loop {
+        let top = stack.last_mut().unwrap();
+        match top.children.next() {
+            Some(Child::Node(child)) => {
+                if !(options.ignore_comments && is_comment(&child)) {
+                    stack.push(Frame::new(child, options));
+                }
+            }
+            Some(Child::Text(text)) => {
+                if !options.ignore_whitespace {
+                    top.hash.write_str(text);
+                }
+            }
+            None => {
+                let frame = stack.pop().unwrap();
+                let (hash, tokens) = (frame.hash.0, frame.tokens);
+                if let Some(out) = out {
+                    out.push(Fingerprint { hash, kind: frame.node.kind(), range: frame.node.range(), tokens });
+                }
+
+                match stack.last_mut() {
+                    Some(parent) => {
+                        parent.hash.write_u64(hash);
+                        parent.tokens += tokens;
+                    }
+                    None => return (hash, tokens),
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Node<'a> {
+    pub fn structural_hash(&self) -> u64 {
+        self.structural_hash_with(HashOptions::default())
+    }
+
+    pub fn structural_hash_with(&self, options: HashOptions) -> u64 {
+        hash_node(self, options, &mut None).0
+    }
+}
+
+// The fingerprint of every node in a tree, computed in one pass.
+pub struct FingerprintIndex {
+    options: HashOptions,
+    fingerprints: Vec<Fingerprint>,
+    by_hash: HashMap<u64, Vec<usize>>,
+    by_node: HashMap<(usize, usize, Kind), usize>,
+}
+
+impl FingerprintIndex {
+    pub fn new(tree: &Tree, options: HashOptions) -> FingerprintIndex {
+        let mut fingerprints = Vec::new();
+        hash_node(&tree.root(), options, &mut Some(&mut fingerprints));
+
+        let mut by_hash: HashMap<u64, Vec<usize>> = HashMap::new();
+        let mut by_node = HashMap::new();
+        for (i, f) in fingerprints.iter().enumerate() {
+            by_hash.entry(f.hash).or_default().push(i);
+            by_node.insert((f.range.start_byte, f.range.end_byte, f.kind), i);
+        }
+
+        FingerprintIndex { options, fingerprints, by_hash, by_node }
+    }
+
+    pub fn options(&self) -> HashOptions {
+        self.options
+    }
+
+    // Every fingerprint, children before their parents.
+    pub fn fingerprints(&self) -> &[Fingerprint] {
+        &self.fingerprints
+    }
+
+    // The fingerprint of `node`, unless it was skipped as a comment.
+    pub fn get(&self, node: &Node) -> Option<&Fingerprint> {
+        self.by_node.get(&(node.start_byte(), node.end_byte(), node.kind())).map(|&i| &self.fingerprints[i])
+    }
+
+    // The nodes with the given hash, e.g. the places a subtree of another tree turns up in this one.
+    pub fn find(&self, hash: u64) -> Vec<&Fingerprint> {
+        self.by_hash.get(&hash).map_or(Vec::new(), |ids| ids.iter().map(|&i| &self.fingerprints[i]).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{Parser, Language};
+
+    #[test]
+    fn test_structural_hash() {
+        let mut parser = Parser::new(Language::Python);
+        let old = parser.parse("def f(a):\n    return a + 1\n\ndef g():\n    pass\n").unwrap();
+        let new = parser.parse("def g():\n    pass\n\n\ndef f(a):  # moved\n    return a+1\n").unwrap();
+        let renamed = parser.parse("def h(b):\n    return b + 2\n").unwrap();
+
+        let no_comments = HashOptions { ignore_comments: true, ..HashOptions::default() };
+        let function = |tree: &Tree, i: usize| tree.root().nodes()
+            .filter(|n| n.kind_name() == "function_definition")
+            .nth(i).unwrap()
+            .structural_hash_with(no_comments);
+        assert_eq!(function(&old, 0), function(&new, 1));
+        assert_ne!(function(&old, 0), function(&renamed, 0));
+        assert_ne!(old.root().structural_hash(), new.root().structural_hash());
+
+        let loose = HashOptions { ignore_comments: true, ignore_names: true, ignore_literals: true, ..HashOptions::default() };
+        let f = old.root().nodes().next().unwrap();
+        assert_eq!(f.structural_hash_with(loose), renamed.root().nodes().next().unwrap().structural_hash_with(loose));
+        assert_ne!(f.structural_hash_with(HashOptions { ignore_whitespace: false, ..loose }),
+            new.root().nodes().nth(1).unwrap().structural_hash_with(HashOptions { ignore_whitespace: false, ..loose }));
+
+        // The function has moved, and the index of the new tree finds it.
+        let index = FingerprintIndex::new(&new, no_comments);
+        let found = index.find(f.structural_hash_with(no_comments));
+        assert_eq!(found.iter().map(|f| f.range.start_point.row).collect::<Vec<_>>(), vec![4]);
+        assert_eq!(index.get(&new.root()).unwrap().hash, new.root().structural_hash_with(no_comments));
+        assert_eq!(index.fingerprints().len(), new.nodes().filter(|n| !is_comment(n)).count());
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        let depth = 20000;
+        let text = format!("x = {}1{}\n", "(".repeat(depth), ")".repeat(depth));
+        let tree = Parser::new(Language::Python).parse(&text).unwrap();
+        let index = FingerprintIndex::new(&tree, HashOptions::default());
+        assert_eq!(index.get(&tree.root()).unwrap().tokens, 2 * depth + 3);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::parse::{Language, Tree, Node, Point, Range};
+use std::collections::HashSet;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FoldKind {
+    Region,
+    Comment,
+    Imports,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FoldingRange {
+    pub start_row: usize,
+    pub end_row: usize,
+    pub kind: FoldKind,
+}
+
+impl FoldKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            FoldKind::Region => "region",
+            FoldKind::Comment => "comment",
+            FoldKind::Imports => "imports",
+        }
+    }
+}
+
+fn import_kinds(lang: Language) -> &'static [&'static str] {
+    match lang {
+        Language::Rust => &["use_declaration", "extern_crate_declaration"],
+        Language::Python => &["import_statement", "import_from_statement", "future_import_statement"],
+        Language::Javascript |
+        Language::Typescript |
+        Language::Julia => &["import_statement"],
+        Language::Go |
+        Language::Java |
+        Language::Scala => &["import_declaration"],
+        Language::C => &["preproc_include"],
+        Language::Cpp => &["preproc_include", "using_declaration"],
+        Language::CSharp => &["using_directive"],
+        Language::Php => &["namespace_use_declaration"],
+        Language::Ocaml => &["open_statement"],
+        _ => &[],
+    }
+}
+
+fn is_comment(node: &Node) -> bool {
+    node.kind_name().contains("comment")
+}
+
+// The last row that actually contains text of `node`; nodes that swallow a trailing newline
+// end at column zero of the following row.
+fn last_row(node: &Node) -> usize {
+    let end = node.end_point();
+    if end.column == 0 && end.row > node.start_point().row {
+        end.row - 1
+    } else {
+        end.row
+    }
+}
+
+impl FoldingRange {
+    pub fn compute(tree: &Tree) -> Vec<FoldingRange> {
+        let mut res = Vec::new();
+        FoldingRange::collect(&tree.root(), import_kinds(tree.language()), &mut res);
+
+        // Several nodes often start on the same row (e.g. a function and its body); keep the
+        // outermost, which `collect` always visits first.
+        let mut seen = HashSet::new();
+        res.retain(|f: &FoldingRange| seen.insert(f.start_row));
+        res.sort_by_key(|f| (f.start_row, f.end_row));
+        res
+    }
+
+    fn collect(node: &Node, imports: &[&str], out: &mut Vec<FoldingRange>) {
+        let mut run: Option<(FoldKind, usize, usize)> = None;
+
+        for child in node.nodes() {
+            let kind = if is_comment(&child) {
+                Some(FoldKind::Comment)
+            } else if imports.contains(&child.kind_name()) {
+                Some(FoldKind::Imports)
+            } else {
+                None
+            };
+
+            let (start, end) = (child.start_point().row, last_row(&child));
+
+            run = match (run, kind) {
+                (Some((k, s, e)), Some(kind)) if k == kind && start <= e + 1 => Some((k, s, end)),
+                (prev, kind) => {
+                    if let Some((k, s, e)) = prev {
+                        if e > s {
+                            out.push(FoldingRange { start_row: s, end_row: e, kind: k });
+                        }
+                    }
+                    kind.map(|k| (k, start, end))
+                }
+            };
+
+            if kind.is_none() && child.is_named() && end > start {
+                out.push(FoldingRange { start_row: start, end_row: end, kind: FoldKind::Region });
+            }
+
+            if kind != Some(FoldKind::Comment) {
+                FoldingRange::collect(&child, imports, out);
+            }
+        }
+
+        if let Some((k, s, e)) = run {
+            if e > s {
+                out.push(FoldingRange { start_row: s, end_row: e, kind: k });
+            }
+        }
+    }
+}
+
+// The ranges of every node enclosing `point`, innermost first, with duplicates removed. An
+// editor's "expand selection" walks forward through this list and "shrink selection" walks back.
+pub fn selection_chain(tree: &Tree, point: Point) -> Vec<Range> {
+    let mut chain: Vec<Range> = Vec::new();
+    let mut node = tree.root().descendant_for_point_range(point, point);
+
+    while let Some(n) = node {
+        let range = n.range();
+        if chain.last().map(|r| (r.start_byte, r.end_byte)) != Some((range.start_byte, range.end_byte)) {
+            chain.push(range);
+        }
+        node = n.parent();
+    }
+
+    chain
+}
+
+// The smallest node range that strictly contains the byte range `start..end`.
+pub fn expand_selection(tree: &Tree, start: usize, end: usize) -> Option<Range> {
+    let mut node = tree.root().descendant_for_byte_range(start, end);
+
+    while let Some(n) = node {
+        if n.start_byte() < start || n.end_byte() > end {
+            return Some(n.range());
+        }
+        node = n.parent();
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::Parser;
+
+    #[test]
+    fn test_python_folds() {
+        let text = "import a\nimport b\n\n# one\n# two\ndef f():\n    x = 1\n    return x\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+
+        assert_eq!(FoldingRange::compute(&tree), vec![
+            FoldingRange { start_row: 0, end_row: 1, kind: FoldKind::Imports },
+            FoldingRange { start_row: 3, end_row: 4, kind: FoldKind::Comment },
+            FoldingRange { start_row: 5, end_row: 7, kind: FoldKind::Region },
+        ]);
+
+        let chain = selection_chain(&tree, Point::new(6, 4));
+        let texts = chain.iter().map(|r| &text[r.start_byte..r.end_byte]).collect::<Vec<_>>();
+        assert_eq!(texts[0], "x");
+        assert_eq!(texts[1], "x = 1");
+
+        let r = expand_selection(&tree, 44, 45).unwrap();
+        assert_eq!(&text[r.start_byte..r.end_byte], "x = 1");
+    }
+}
@@ -0,0 +1,523 @@
+use crate::parse::{Language, Tree, Node, Child};
+use std::collections::HashMap;
+
+// A document in the style of Wadler's "prettier printer": text, plus line breaks that are
+// either all taken or all flattened within each group.
+#[derive(Debug, Clone)]
+pub enum Doc {
+    Nil,
+    Text(String),
+    // A space when flat, a newline when broken.
+    Line,
+    // Nothing when flat, a newline when broken.
+    SoftLine,
+    // Always a newline; forces every enclosing group to break.
+    HardLine,
+    Concat(Vec<Doc>),
+    // Indent every line break inside by one level.
+    Nest(Box<Doc>),
+    Group(Box<Doc>),
+    // The first doc if the enclosing group is broken, otherwise the second.
+    IfBreak(Box<Doc>, Box<Doc>),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FormatRule {
+    // Comma-separated lists such as `[1, 2]` or `f(a, b)`, broken one item per line (with a
+    // trailing separator) when they don't fit. `padded` lists get spaces inside the delimiters
+    // when flat, like `{ a: 1 }`.
+    List {
+        open: &'static str,
+        close: &'static str,
+        separator: &'static str,
+        padded: bool,
+    },
+    // A sequence of statements, one per line, keeping at most one blank line between them.
+    Statements,
+    // A block of statements between delimiters, indented on their own lines.
+    Block {
+        open: &'static str,
+        close: &'static str,
+    },
+    // A statement whose children after `open` are an indented body, like Python's
+    // `if x:` followed by statements.
+    Compound {
+        open: &'static str,
+    },
+    // Like `Compound`, but dedented back to the level of the statement it belongs to, like
+    // Python's `else:`.
+    Clause {
+        open: &'static str,
+    },
+    // Every child separated by a single space, e.g. binary operators.
+    Spaced,
+    // Printed exactly as written, e.g. string literals.
+    Verbatim,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+impl Doc {
+    pub fn text(s: impl Into<String>) -> Doc {
+        Doc::Text(s.into())
+    }
+
+    pub fn nest(doc: Doc) -> Doc {
+        Doc::Nest(Box::new(doc))
+    }
+
+    pub fn group(doc: Doc) -> Doc {
+        Doc::Group(Box::new(doc))
+    }
+
+    pub fn if_break(broken: Doc, flat: Doc) -> Doc {
+        Doc::IfBreak(Box::new(broken), Box::new(flat))
+    }
+
+    pub fn render(&self, width: usize, indent: usize) -> String {
+        let mut out = String::new();
+        let mut column = 0;
+        let mut stack = vec![(0, Mode::Break, self)];
+
+        while let Some((level, mode, doc)) = stack.pop() {
+            match doc {
+                Doc::Nil => {}
+                Doc::Text(s) => {
+                    out.push_str(s);
+                    column = match s.rfind('\n') {
+                        Some(i) => s.len() - i - 1,
+                        None => column + s.chars().count(),
+                    };
+                }
+                Doc::Line | Doc::SoftLine if mode == Mode::Flat => {
+                    if let Doc::Line = doc {
+                        out.push(' ');
+                        column += 1;
+                    }
+                }
+                Doc::Line | Doc::SoftLine | Doc::HardLine => {
+                    while out.ends_with(' ') {
+                        out.pop();
+                    }
+                    out.push('\n');
+                    out.push_str(&" ".repeat(level));
+                    column = level;
+                }
+                Doc::Concat(docs) => {
+                    stack.extend(docs.iter().rev().map(|d| (level, mode, d)));
+                }
+                Doc::Nest(doc) => stack.push((level + indent, mode, doc)),
+                Doc::Group(doc) => {
+                    let mode = if mode == Mode::Flat || fits(width as isize - column as isize, doc, &stack) {
+                        Mode::Flat
+                    } else {
+                        Mode::Break
+                    };
+                    stack.push((level, mode, doc));
+                }
+                Doc::IfBreak(broken, flat) => {
+                    stack.push((level, mode, if mode == Mode::Break { broken } else { flat }));
+                }
+            }
+        }
+
+        out
+    }
+}
+
+// Whether `doc` fits flat in `remaining` columns, along with whatever follows it up to the next
+// possible line break.
+fn fits(mut remaining: isize, doc: &Doc, rest: &[(usize, Mode, &Doc)]) -> bool {
+    let mut stack = vec![(Mode::Flat, doc)];
+    let mut rest = rest.iter().rev();
+
+    while remaining >= 0 {
+        let (mode, doc) = match stack.pop() {
+            Some(item) => item,
+            None => match rest.next() {
+                Some(&(_, mode, doc)) => (mode, doc),
+                None => return true,
+            },
+        };
+
+        match doc {
+            Doc::Nil => {}
+            Doc::Text(s) => {
+                if let Some(i) = s.find('\n') {
+                    return remaining >= i as isize;
+                }
+                remaining -= s.chars().count() as isize;
+            }
+            Doc::Line if mode == Mode::Flat => remaining -= 1,
+            Doc::SoftLine if mode == Mode::Flat => {}
+            Doc::HardLine if mode == Mode::Flat => return false,
+            Doc::Line | Doc::SoftLine | Doc::HardLine => return true,
+            Doc::Concat(docs) => stack.extend(docs.iter().rev().map(|d| (mode, d))),
+            Doc::Nest(doc) | Doc::Group(doc) => stack.push((mode, doc)),
+            Doc::IfBreak(broken, flat) => stack.push((mode, if mode == Mode::Break { broken } else { flat })),
+        }
+    }
+
+    false
+}
+
+fn default_rules(lang: Language) -> Vec<(&'static str, FormatRule)> {
+    let list = |open, close| FormatRule::List { open, close, separator: ",", padded: false };
+    let compound = FormatRule::Compound { open: ":" };
+    let clause = FormatRule::Clause { open: ":" };
+
+    match lang {
+        Language::Python => vec![
+            ("module", FormatRule::Statements),
+            ("decorated_definition", FormatRule::Statements),
+            ("function_definition", compound),
+            ("class_definition", compound),
+            ("if_statement", compound),
+            ("for_statement", compound),
+            ("while_statement", compound),
+            ("try_statement", compound),
+            ("with_statement", compound),
+            ("elif_clause", clause),
+            ("else_clause", clause),
+            ("except_clause", clause),
+            ("finally_clause", clause),
+            ("assignment", FormatRule::Spaced),
+            ("augmented_assignment", FormatRule::Spaced),
+            ("binary_operator", FormatRule::Spaced),
+            ("comparison_operator", FormatRule::Spaced),
+            ("boolean_operator", FormatRule::Spaced),
+            ("argument_list", list("(", ")")),
+            ("parameters", list("(", ")")),
+            ("list", list("[", "]")),
+            ("tuple", list("(", ")")),
+            ("set", list("{", "}")),
+            ("dictionary", list("{", "}")),
+        ],
+        Language::Javascript | Language::Typescript => vec![
+            ("program", FormatRule::Statements),
+            ("statement_block", FormatRule::Block { open: "{", close: "}" }),
+            ("class_body", FormatRule::Block { open: "{", close: "}" }),
+            ("object", FormatRule::List { open: "{", close: "}", separator: ",", padded: true }),
+            ("array", list("[", "]")),
+            ("arguments", list("(", ")")),
+            ("formal_parameters", list("(", ")")),
+            ("variable_declarator", FormatRule::Spaced),
+            ("assignment_expression", FormatRule::Spaced),
+            ("augmented_assignment_expression", FormatRule::Spaced),
+            ("binary_expression", FormatRule::Spaced),
+            ("ternary_expression", FormatRule::Spaced),
+            ("regex", FormatRule::Verbatim),
+            ("template_string", FormatRule::Verbatim),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+// A child along with the whitespace that precedes it.
+type Item<'a> = (&'a str, Option<Node<'a>>, Doc);
+
+fn is_line_comment(node: &Node) -> bool {
+    let text = node.text();
+    node.kind_name().contains("comment") && (text.starts_with("//") || text.starts_with('#'))
+}
+
+fn is_token(item: &Item, text: &str) -> bool {
+    matches!(&item.1, Some(n) if !n.is_named() && n.text() == text)
+}
+
+fn indented(doc: Doc) -> Doc {
+    Doc::nest(Doc::Concat(vec![Doc::HardLine, doc]))
+}
+
+fn newlines(trivia: &str) -> usize {
+    trivia.matches('\n').count()
+}
+
+pub struct Formatter {
+    pub width: usize,
+    pub indent: usize,
+    rules: HashMap<&'static str, FormatRule>,
+}
+
+impl Formatter {
+    pub fn new(lang: Language) -> Formatter {
+        Formatter {
+            width: 80,
+            indent: if lang == Language::Python { 4 } else { 2 },
+            rules: default_rules(lang).into_iter().collect(),
+        }
+    }
+
+    pub fn set_rule(&mut self, kind: &'static str, rule: FormatRule) {
+        self.rules.insert(kind, rule);
+    }
+
+    pub fn format(&self, tree: &Tree) -> String {
+        let mut res = self.doc(&tree.root()).render(self.width, self.indent);
+        let len = res.trim_end().len();
+        res.truncate(len);
+        res.push('\n');
+        res
+    }
+
+    pub fn doc(&self, node: &Node) -> Doc {
+        if node.nodes().next().is_none() || node.kind_name().contains("string") || node.kind_name().contains("comment") {
+            return Doc::text(node.text());
+        }
+
+        match self.rules.get(node.kind_name()) {
+            Some(FormatRule::Verbatim) => Doc::text(node.text()),
+            Some(FormatRule::Statements) => self.lines(self.items(node)),
+            Some(FormatRule::Spaced) => self.tokens(self.items(node), true),
+            Some(&FormatRule::Block { open, close }) => {
+                let mut items = self.items(node);
+                if matches!(items.last(), Some(item) if is_token(item, close)) {
+                    items.pop();
+                }
+                if matches!(items.first(), Some(item) if is_token(item, open)) {
+                    items.remove(0);
+                }
+
+                if items.is_empty() {
+                    Doc::text(format!("{}{}", open, close))
+                } else {
+                    Doc::Concat(vec![Doc::text(open), indented(self.lines(items)), Doc::HardLine, Doc::text(close)])
+                }
+            }
+            Some(&FormatRule::Compound { open }) | Some(&FormatRule::Clause { open }) => self.compound(node, open),
+            Some(&FormatRule::List { open, close, separator, padded }) => self.list(node, open, close, separator, padded),
+            None => self.tokens(self.items(node), false),
+        }
+    }
+
+    fn rule(&self, node: &Option<Node>) -> Option<FormatRule> {
+        node.as_ref().and_then(|n| self.rules.get(n.kind_name()).cloned())
+    }
+
+    // The children of `node`, with the trivia before each and any stray non-whitespace trivia
+    // (text the grammar didn't cover with a node) kept as its own item. Line continuations count
+    // as whitespace, since the items are laid out afresh.
+    fn items<'a>(&self, node: &Node<'a>) -> Vec<Item<'a>> {
+        let mut items = Vec::new();
+        let mut trivia = "";
+
+        for ch in node.children() {
+            match ch {
+                Child::Text(text) => {
+                    if text.split_whitespace().all(|t| t == "\\") {
+                        trivia = text;
+                    } else {
+                        items.push((trivia, None, Doc::text(text.trim())));
+                        trivia = "";
+                    }
+                }
+                Child::Node(n) => {
+                    let doc = self.doc(&n);
+                    items.push((trivia, Some(n), doc));
+                    trivia = "";
+                }
+            }
+        }
+
+        items
+    }
+
+    // Joins items on one line, separated by a space wherever the input had whitespace (or always,
+    // if `spaced`).
+    fn tokens(&self, items: Vec<Item>, spaced: bool) -> Doc {
+        let mut docs = Vec::new();
+        let mut after_line_comment = false;
+
+        for (i, (trivia, n, doc)) in items.into_iter().enumerate() {
+            let is_block = matches!(self.rule(&n), Some(FormatRule::Block { .. }));
+
+            if after_line_comment {
+                docs.push(Doc::HardLine);
+            } else if i > 0 && (spaced || is_block || !trivia.is_empty()) {
+                docs.push(Doc::text(" "));
+            }
+
+            after_line_comment = matches!(&n, Some(n) if is_line_comment(n));
+            docs.push(doc);
+        }
+
+        Doc::Concat(docs)
+    }
+
+    // Puts each item on its own line, except for trailing comments and punctuation such as `;`.
+    fn lines(&self, items: Vec<Item>) -> Doc {
+        let mut docs = Vec::new();
+        let mut after_line_comment = false;
+
+        for (i, (trivia, n, doc)) in items.into_iter().enumerate() {
+            let is_comment = matches!(&n, Some(n) if n.kind_name().contains("comment"));
+            let is_punctuation = matches!(&n, Some(n) if !n.is_named());
+
+            if i > 0 {
+                if is_comment && newlines(trivia) == 0 && !after_line_comment {
+                    // A trailing comment stays on the line of the statement it follows.
+                    docs.push(Doc::text(" "));
+                } else if is_punctuation && !after_line_comment {
+                } else {
+                    docs.push(Doc::HardLine);
+                    if newlines(trivia) > 1 {
+                        docs.push(Doc::HardLine);
+                    }
+                }
+            }
+
+            after_line_comment = matches!(&n, Some(n) if is_line_comment(n));
+            docs.push(doc);
+        }
+
+        Doc::Concat(docs)
+    }
+
+    // A header up to `open`, followed by an indented body and then any clauses at the level of
+    // the header.
+    fn compound(&self, node: &Node, open: &str) -> Doc {
+        let mut items = self.items(node);
+        let header_end = match items.iter().position(|item| is_token(item, open)) {
+            Some(i) => i + 1,
+            None => return self.tokens(items, false),
+        };
+
+        let mut rest = items.split_off(header_end);
+        items[header_end - 1].0 = "";
+
+        // A comment on the header's line stays there, like a trailing comment.
+        let is_comment = |n: &Option<Node>| matches!(n, Some(n) if n.kind_name().contains("comment"));
+        if matches!(rest.first(), Some((trivia, n, _)) if is_comment(n) && newlines(trivia) == 0) {
+            items.push(rest.remove(0));
+        }
+
+        let mut docs = vec![self.tokens(items, false)];
+        let mut body = Vec::new();
+
+        for item in rest {
+            if let Some(FormatRule::Clause { .. }) = self.rule(&item.1) {
+                if !body.is_empty() {
+                    docs.push(indented(self.lines(body)));
+                    body = Vec::new();
+                }
+                docs.push(Doc::HardLine);
+                docs.push(item.2);
+            } else {
+                body.push(item);
+            }
+        }
+
+        if !body.is_empty() {
+            docs.push(indented(self.lines(body)));
+        }
+
+        Doc::Concat(docs)
+    }
+
+    fn list(&self, node: &Node, open: &str, close: &str, separator: &str, padded: bool) -> Doc {
+        let items = self.items(node).into_iter()
+            .filter(|(_, n, _)| match n {
+                Some(n) if !n.is_named() => {
+                    let t = n.text();
+                    t != open && t != close && t != separator
+                }
+                _ => true,
+            })
+            .collect::<Vec<_>>();
+
+        if items.is_empty() {
+            return Doc::text(format!("{}{}", open, close));
+        }
+
+        let is_comment = |n: &Option<Node>| matches!(n, Some(n) if n.kind_name().contains("comment"));
+        let last_value = items.iter().rposition(|(_, n, _)| !is_comment(n));
+        // The close may be missing from a tree with errors.
+        let text = node.text();
+        let had_trailing = text.ends_with(close) && text[..text.len() - close.len()].trim_end().ends_with(separator);
+
+        let mut body = Vec::new();
+        let mut after_line_comment = false;
+        for (i, (trivia, n, doc)) in items.into_iter().enumerate() {
+            if i > 0 {
+                if after_line_comment {
+                    body.push(Doc::HardLine);
+                } else if is_comment(&n) && newlines(trivia) == 0 {
+                    body.push(Doc::text(" "));
+                } else {
+                    body.push(Doc::Line);
+                }
+            }
+
+            after_line_comment = matches!(&n, Some(n) if is_line_comment(n));
+            let value = !is_comment(&n);
+            body.push(doc);
+
+            if value && Some(i) == last_value {
+                // `(1,)` needs its trailing comma even when flat.
+                let flat = if had_trailing && i == 0 { Doc::text(separator) } else { Doc::Nil };
+                body.push(Doc::if_break(Doc::text(separator), flat));
+            } else if value && Some(i) < last_value {
+                body.push(Doc::text(separator));
+            }
+        }
+
+        let edge = if padded { Doc::Line } else { Doc::SoftLine };
+        Doc::group(Doc::Concat(vec![
+            Doc::text(open),
+            Doc::nest(Doc::Concat(vec![edge.clone(), Doc::Concat(body)])),
+            edge,
+            Doc::text(close),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::Parser;
+
+    #[test]
+    fn test_format_python() {
+        let text = "def f( a,b ):\n  x=[1,2,  3] # c\n\n\n\n  return g(x,(1,))\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let mut formatter = Formatter::new(Language::Python);
+        assert_eq!(formatter.format(&tree), "def f(a, b):\n    x = [1, 2, 3] # c\n\n    return g(x, (1,))\n");
+
+        formatter.width = 14;
+        assert_eq!(formatter.format(&tree), "def f(a, b):\n    x = [\n        1,\n        2,\n        3,\n    ] # c\n\n    return g(\n        x,\n        (1,),\n    )\n");
+    }
+
+    #[test]
+    fn test_format_python_clauses() {
+        let text = "if a:  # why\n  b()\nelif c: d()\nelse :\n  pass\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let formatter = Formatter::new(Language::Python);
+        assert_eq!(formatter.format(&tree), "if a: # why\n    b()\nelif c:\n    d()\nelse:\n    pass\n");
+    }
+
+    #[test]
+    fn test_format_python_continuations() {
+        let text = "x = [1, \\\n    2]\ny = 1 + \\\n    2\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let formatter = Formatter::new(Language::Python);
+        assert_eq!(formatter.format(&tree), "x = [1, 2]\ny = 1 + 2\n");
+
+        // The list's text ends in the comment, not in a close it could cut off.
+        let tree = Parser::new(Language::Python).parse("f(a, [1, 2  # é\n)\n").unwrap();
+        assert!(tree.root().has_error());
+        formatter.format(&tree);
+    }
+
+    #[test]
+    #[cfg(feature = "lang_javascript")]
+    fn test_format_javascript() {
+        let text = "let o = {a: 1,  b: [true,null]};\nfunction f(){ return o }\n";
+        let tree = Parser::new(Language::Javascript).parse(text).unwrap();
+        let formatter = Formatter::new(Language::Javascript);
+        assert_eq!(formatter.format(&tree), "let o = { a: 1, b: [true, null] };\nfunction f() {\n  return o\n}\n");
+    }
+}
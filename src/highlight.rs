@@ -0,0 +1,390 @@
+use crate::parse::{Language, Tree};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::fmt::Write;
+use tree_sitter as ts;
+use failure::{Error, format_err};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Scope {
+    Keyword,
+    String,
+    Number,
+    Constant,
+    Comment,
+    Function,
+    Type,
+    Property,
+    Operator,
+    Punctuation,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub scope: Scope,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Format {
+    Ansi,
+    Html,
+}
+
+pub struct Highlighter {
+    sheet: ts::PropertySheet<Option<Scope>>,
+}
+
+impl Scope {
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::Keyword => "keyword",
+            Scope::String => "string",
+            Scope::Number => "number",
+            Scope::Constant => "constant",
+            Scope::Comment => "comment",
+            Scope::Function => "function",
+            Scope::Type => "type",
+            Scope::Property => "property",
+            Scope::Operator => "operator",
+            Scope::Punctuation => "punctuation",
+        }
+    }
+
+    fn ansi(self) -> &'static str {
+        match self {
+            Scope::Keyword => "\x1b[35m",
+            Scope::String => "\x1b[32m",
+            Scope::Number => "\x1b[36m",
+            Scope::Constant => "\x1b[36m",
+            Scope::Comment => "\x1b[90m",
+            Scope::Function => "\x1b[34m",
+            Scope::Type => "\x1b[33m",
+            Scope::Property => "\x1b[31m",
+            Scope::Operator => "\x1b[1m",
+            Scope::Punctuation => "\x1b[37m",
+        }
+    }
+
+    // Guess a scope from the name of a node kind alone. This works surprisingly well across
+    // grammars, since most of them follow the naming conventions of the early tree-sitter parsers.
+    fn from_kind(name: &str, named: bool) -> Option<Scope> {
+        if !named {
+            return if name.chars().all(|c| c.is_alphabetic() || c == '_') {
+                Some(Scope::Keyword)
+            } else if name.chars().all(|c| "()[]{},;.:".contains(c)) {
+                Some(Scope::Punctuation)
+            } else {
+                Some(Scope::Operator)
+            };
+        }
+
+        Some(match name {
+            _ if name.contains("comment") => Scope::Comment,
+            _ if name.contains("string") || name == "char_literal" || name == "heredoc_body" => Scope::String,
+            _ if name.contains("integer") || name.contains("float") || name.contains("number") => Scope::Number,
+            "true" | "false" | "boolean_literal" | "null" | "none" | "nil" | "undefined" => Scope::Constant,
+            "type_identifier" | "primitive_type" | "predefined_type" | "builtin_type" => Scope::Type,
+            "field_identifier" | "property_identifier" | "shorthand_property_identifier" => Scope::Property,
+            _ => return None,
+        })
+    }
+}
+
+impl FromStr for Scope {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Error> {
+        Ok(match text {
+            "keyword" => Scope::Keyword,
+            "string" => Scope::String,
+            "number" => Scope::Number,
+            "constant" => Scope::Constant,
+            "comment" => Scope::Comment,
+            "function" => Scope::Function,
+            "type" => Scope::Type,
+            "property" => Scope::Property,
+            "operator" => Scope::Operator,
+            "punctuation" => Scope::Punctuation,
+            _ => return Err(format_err!("invalid scope '{}'", text))
+        })
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Error> {
+        Ok(match text {
+            "ansi" => Format::Ansi,
+            "html" => Format::Html,
+            _ => return Err(format_err!("invalid format '{}'", text))
+        })
+    }
+}
+
+// Scopes that depend on the parent of a node, as (parent kind, child kind, scope).
+fn context_rules(lang: Language) -> &'static [(&'static str, &'static str, Scope)] {
+    match lang {
+        Language::Rust => &[
+            ("function_item", "identifier", Scope::Function),
+            ("function_signature_item", "identifier", Scope::Function),
+            ("call_expression", "identifier", Scope::Function),
+            ("macro_invocation", "identifier", Scope::Function),
+        ],
+        Language::Python => &[
+            ("function_definition", "identifier", Scope::Function),
+            ("class_definition", "identifier", Scope::Type),
+            ("call", "identifier", Scope::Function),
+            ("decorator", "identifier", Scope::Function),
+        ],
+        Language::Javascript | Language::Typescript => &[
+            ("function_declaration", "identifier", Scope::Function),
+            ("function", "identifier", Scope::Function),
+            ("call_expression", "identifier", Scope::Function),
+            ("method_definition", "property_identifier", Scope::Function),
+            ("class_declaration", "identifier", Scope::Type),
+        ],
+        Language::C | Language::Cpp => &[
+            ("function_declarator", "identifier", Scope::Function),
+            ("call_expression", "identifier", Scope::Function),
+        ],
+        Language::Go => &[
+            ("function_declaration", "identifier", Scope::Function),
+            ("method_declaration", "field_identifier", Scope::Function),
+            ("call_expression", "identifier", Scope::Function),
+        ],
+        Language::Ruby => &[
+            ("method", "identifier", Scope::Function),
+            ("class", "constant", Scope::Type),
+            ("module", "constant", Scope::Type),
+        ],
+        _ => &[],
+    }
+}
+
+// A transition to `state_id` on a node of the given kind: (kind, named, state_id).
+type Target<'a> = (&'a str, bool, usize);
+
+struct SheetBuilder {
+    states: Vec<ts::PropertyStateJSON>,
+    property_sets: Vec<HashMap<String, String>>,
+    set_ids: HashMap<Scope, usize>,
+}
+
+impl SheetBuilder {
+    fn new() -> SheetBuilder {
+        SheetBuilder {
+            states: Vec::new(),
+            property_sets: vec![HashMap::new()],
+            set_ids: HashMap::new(),
+        }
+    }
+
+    fn property_set(&mut self, scope: Option<Scope>) -> usize {
+        let scope = match scope {
+            Some(scope) => scope,
+            None => return 0,
+        };
+
+        let property_sets = &mut self.property_sets;
+        *self.set_ids.entry(scope).or_insert_with(|| {
+            let mut props = HashMap::new();
+            props.insert("scope".to_string(), scope.name().to_string());
+            property_sets.push(props);
+            property_sets.len() - 1
+        })
+    }
+
+    // Highlighted nodes are never descended into, so their states don't need any transitions.
+    fn state(&mut self, scope: Option<Scope>) -> usize {
+        let property_set_id = self.property_set(scope);
+        self.states.push(ts::PropertyStateJSON {
+            id: Some(self.states.len()),
+            property_set_id,
+            transitions: Vec::new(),
+            default_next_state_id: 0,
+        });
+        self.states.len() - 1
+    }
+
+    fn transition(kind: &str, named: bool, state_id: usize) -> ts::PropertyTransitionJSON {
+        ts::PropertyTransitionJSON {
+            kind: Some(kind.to_string()),
+            named: Some(named),
+            index: None,
+            field: None,
+            text: None,
+            state_id,
+        }
+    }
+
+    // Build a property sheet equivalent to a stylesheet with a `kind { scope: ... }` rule for
+    // every kind we can classify, plus a `parent > kind { scope: ... }` rule for every context rule.
+    fn build(mut self, lang: Language) -> Result<ts::PropertySheet<Option<Scope>>, Error> {
+        let ts_lang = lang.ts_language();
+        let root = self.state(None);
+
+        let mut base = Vec::new();
+        for id in 0..ts_lang.node_kind_count() as u16 {
+            let name = ts_lang.node_kind_for_id(id);
+            let named = ts_lang.node_kind_is_named(id);
+            if let Some(scope) = Scope::from_kind(name, named) {
+                base.push((name, named, self.state(Some(scope))));
+            }
+        }
+
+        let mut parents: Vec<(&str, usize, Vec<Target>)> = Vec::new();
+        for &(parent, kind, scope) in context_rules(lang) {
+            let child = (kind, true, self.state(Some(scope)));
+            if let Some((_, _, children)) = parents.iter_mut().find(|(p, _, _)| *p == parent) {
+                children.push(child);
+            } else {
+                let state_id = self.state(None);
+                parents.push((parent, state_id, vec![child]));
+            }
+        }
+
+        base.extend(parents.iter().map(|&(parent, state_id, _)| (parent, true, state_id)));
+
+        for (_, state_id, children) in parents {
+            self.states[state_id].transitions = children.iter().chain(base.iter())
+                .map(|&(kind, named, state_id)| SheetBuilder::transition(kind, named, state_id))
+                .collect();
+        }
+
+        self.states[root].transitions = base.iter()
+            .map(|&(kind, named, state_id)| SheetBuilder::transition(kind, named, state_id))
+            .collect();
+
+        let json = serde_json::to_string(&ts::PropertySheetJSON {
+            states: self.states,
+            property_sets: self.property_sets,
+        })?;
+
+        let sheet: ts::PropertySheet = ts::PropertySheet::new(ts_lang, &json)?;
+        sheet.map(|props| props.get("scope").map_or(Ok(None), |s| Scope::from_str(s).map(Some)))
+    }
+}
+
+impl Highlighter {
+    pub fn new(lang: Language) -> Result<Highlighter, Error> {
+        Ok(Highlighter {
+            sheet: SheetBuilder::new().build(lang)?,
+        })
+    }
+
+    // Returns non-overlapping spans, in document order, for every node with a scope.
+    pub fn highlight(&self, tree: &Tree) -> Vec<Span> {
+        let mut cursor = tree.ts_tree().walk_with_properties(&self.sheet, tree.text().as_bytes());
+        let mut spans = Vec::new();
+
+        loop {
+            let node = cursor.node();
+            if let Some(scope) = *cursor.node_properties() {
+                if node.start_byte() < node.end_byte() {
+                    spans.push(Span {
+                        start: node.start_byte(),
+                        end: node.end_byte(),
+                        scope,
+                    });
+                }
+            } else if cursor.goto_first_child() {
+                continue;
+            }
+
+            loop {
+                if cursor.goto_next_sibling() {
+                    break;
+                }
+
+                if !cursor.goto_parent() {
+                    return spans;
+                }
+            }
+        }
+    }
+
+    pub fn render(&self, tree: &Tree, format: Format) -> String {
+        let spans = self.highlight(tree);
+        match format {
+            Format::Ansi => render_ansi(tree.text(), &spans),
+            Format::Html => render_html(tree.text(), &spans),
+        }
+    }
+}
+
+pub fn render_ansi(text: &str, spans: &[Span]) -> String {
+    let mut res = String::new();
+    let mut offset = 0;
+
+    for span in spans {
+        res.push_str(&text[offset..span.start]);
+        res.push_str(span.scope.ansi());
+        res.push_str(&text[span.start..span.end]);
+        res.push_str("\x1b[0m");
+        offset = span.end;
+    }
+
+    res.push_str(&text[offset..]);
+    res
+}
+
+fn escape_html(res: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            c => res.push(c),
+        }
+    }
+}
+
+pub fn render_html(text: &str, spans: &[Span]) -> String {
+    let mut res = String::from("<pre class=\"hornbeam\">");
+    let mut offset = 0;
+
+    for span in spans {
+        escape_html(&mut res, &text[offset..span.start]);
+        write!(res, "<span class=\"hb-{}\">", span.scope.name()).unwrap();
+        escape_html(&mut res, &text[span.start..span.end]);
+        res.push_str("</span>");
+        offset = span.end;
+    }
+
+    escape_html(&mut res, &text[offset..]);
+    res.push_str("</pre>\n");
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::Parser;
+
+    #[test]
+    fn test_highlight_python() {
+        let text = "def foo(x):\n    return bar(x, 1)  # hi\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let spans = Highlighter::new(Language::Python).unwrap().highlight(&tree);
+
+        let scoped = |scope| spans.iter()
+            .filter(|s| s.scope == scope)
+            .map(|s| &text[s.start..s.end])
+            .collect::<Vec<_>>();
+
+        assert_eq!(scoped(Scope::Keyword), vec!["def", "return"]);
+        assert_eq!(scoped(Scope::Function), vec!["foo", "bar"]);
+        assert_eq!(scoped(Scope::Number), vec!["1"]);
+        assert_eq!(scoped(Scope::Comment), vec!["# hi"]);
+    }
+
+    #[test]
+    fn test_render_html() {
+        let spans = [Span { start: 0, end: 2, scope: Scope::Keyword }];
+        assert_eq!(render_html("if a<b", &spans), "<pre class=\"hornbeam\"><span class=\"hb-keyword\">if</span> a&lt;b</pre>\n");
+    }
+}
@@ -0,0 +1,241 @@
+use crate::parse::{Parser, Tree, Node, Language, Range, ParseError, CancellationFlag};
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Describes a region of a host document that should be re-parsed with another grammar.
+#[derive(Debug, Clone)]
+pub struct Injection {
+    // The node kind in the host language that holds the embedded text, and optionally the kind
+    // of its parent (html uses `raw_text` for both <script> and <style> bodies).
+    pub parent: Option<&'static str>,
+    pub kind: &'static str,
+    pub lang: Language,
+    // Parse every matching region together as one document (e.g. the code in an ERB template),
+    // rather than each region on its own (e.g. every html <script> element).
+    pub combined: bool,
+}
+
+impl Injection {
+    pub fn new(parent: Option<&'static str>, kind: &'static str, lang: Language, combined: bool) -> Injection {
+        Injection {
+            parent,
+            kind,
+            lang,
+            combined,
+        }
+    }
+
+    fn matches(&self, node: &Node) -> bool {
+        if node.kind_name() != self.kind {
+            return false;
+        }
+
+        match (self.parent, node.parent()) {
+            (None, _) => true,
+            (Some(kind), Some(parent)) => parent.kind_name() == kind,
+            (Some(_), None) => false,
+        }
+    }
+
+    // ERB and EJS share a grammar, and differ only in the language of the code inside `<% %>`.
+    pub fn template(code: Language) -> Vec<Injection> {
+        vec![
+            Injection::new(None, "content", Language::Html, true),
+            Injection::new(None, "code", code, true),
+        ]
+    }
+
+    // The language of the code in a template, from the template's file extension.
+    pub fn template_language(ext: &str) -> Option<Language> {
+        match ext {
+            "erb" => Some(Language::Ruby),
+            "ejs" => Some(Language::Javascript),
+            _ => None,
+        }
+    }
+
+    pub fn defaults(lang: Language) -> Vec<Injection> {
+        match lang {
+            Language::Html => vec![
+                Injection::new(Some("script_element"), "raw_text", Language::Javascript, false),
+                Injection::new(Some("style_element"), "raw_text", Language::Css, false),
+            ],
+            // The code could be in any language; callers that know it use `template`.
+            Language::EmbeddedTemplate => vec![
+                Injection::new(None, "content", Language::Html, true),
+            ],
+            Language::Php => vec![
+                Injection::new(None, "text", Language::Html, true),
+            ],
+            _ => Vec::new(),
+        }
+    }
+}
+
+pub struct Layer<'a> {
+    pub tree: Tree<'a>,
+    pub ranges: Vec<Range>,
+}
+
+pub struct LayeredTree<'a> {
+    layers: Vec<Layer<'a>>,
+}
+
+pub struct LayeredParser {
+    lang: Language,
+    parsers: HashMap<Language, Parser>,
+    rules: HashMap<Language, Vec<Injection>>,
+    timeout: Option<Duration>,
+    cancellation_flag: Option<CancellationFlag>,
+}
+
+impl<'a> LayeredTree<'a> {
+    pub fn root(&'a self) -> Node<'a> {
+        self.layers[0].tree.root()
+    }
+
+    pub fn layers(&self) -> &[Layer<'a>] {
+        &self.layers
+    }
+
+    // Every node in every layer, host language first.
+    pub fn nodes(&'a self) -> impl Iterator<Item=Node<'a>> {
+        self.layers.iter().flat_map(|layer| layer.tree.nodes())
+    }
+}
+
+impl LayeredParser {
+    pub fn new(lang: Language) -> LayeredParser {
+        LayeredParser {
+            lang,
+            parsers: HashMap::new(),
+            rules: HashMap::new(),
+            timeout: None,
+            cancellation_flag: None,
+        }
+    }
+
+    // Applies to each layer's parse on its own, rather than to the whole document.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+        for parser in self.parsers.values_mut() {
+            parser.set_timeout(timeout);
+        }
+    }
+
+    pub fn set_cancellation_flag(&mut self, flag: Option<CancellationFlag>) {
+        for parser in self.parsers.values_mut() {
+            parser.set_cancellation_flag(flag.clone());
+        }
+        self.cancellation_flag = flag;
+    }
+
+    pub fn set_injections(&mut self, host: Language, injections: Vec<Injection>) {
+        self.rules.insert(host, injections);
+    }
+
+    fn injections(&mut self, host: Language) -> &[Injection] {
+        self.rules.entry(host).or_insert_with(|| Injection::defaults(host))
+    }
+
+    fn parser(&mut self, lang: Language) -> &mut Parser {
+        let (timeout, flag) = (self.timeout, &self.cancellation_flag);
+        self.parsers.entry(lang).or_insert_with(|| {
+            let mut parser = Parser::new(lang);
+            parser.set_timeout(timeout);
+            parser.set_cancellation_flag(flag.clone());
+            parser
+        })
+    }
+
+    pub fn parse<'a>(&mut self, text: &'a str) -> Result<LayeredTree<'a>, ParseError> {
+        let lang = self.lang;
+        let mut layers = vec![Layer {
+            tree: self.parser(lang).parse(text)?,
+            ranges: Vec::new(),
+        }];
+
+        let mut index = 0;
+        while index < layers.len() {
+            let host = layers[index].tree.language();
+            let injections = self.injections(host).to_vec();
+
+            let mut pending = Vec::new();
+            for injection in injections.iter().filter(|i| i.lang.is_compiled_in()) {
+                let ranges = layers[index].tree.nodes()
+                    .filter(|n| injection.matches(n))
+                    .map(|n| n.range())
+                    .collect::<Vec<_>>();
+
+                if ranges.is_empty() {
+                    continue;
+                }
+
+                if injection.combined {
+                    pending.push((injection.lang, ranges));
+                } else {
+                    pending.extend(ranges.into_iter().map(|r| (injection.lang, vec![r])));
+                }
+            }
+
+            for (lang, ranges) in pending {
+                let tree = self.parser(lang).parse_ranges(text, &ranges)?;
+                layers.push(Layer { tree, ranges });
+            }
+
+            index += 1;
+        }
+
+        Ok(LayeredTree { layers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(all(feature = "lang_html", feature = "lang_javascript", feature = "lang_css"))]
+    fn test_html_script() {
+        let mut parser = LayeredParser::new(Language::Html);
+        let tree = parser.parse("<p>hi</p><script>foo(1);</script><style>p { color: red; }</style>").unwrap();
+
+        let langs = tree.layers().iter().map(|l| l.tree.language()).collect::<Vec<_>>();
+        assert_eq!(langs, vec![Language::Html, Language::Javascript, Language::Css]);
+
+        let calls = tree.nodes()
+            .filter(|n| n.language() == Language::Javascript && n.kind_name() == "call_expression")
+            .map(|n| n.text())
+            .collect::<Vec<_>>();
+        assert_eq!(calls, vec!["foo(1)"]);
+    }
+
+    #[test]
+    #[cfg(all(feature = "lang_embedded_template", feature = "lang_html", not(feature = "lang_ruby")))]
+    fn test_missing_language() {
+        let mut parser = LayeredParser::new(Language::EmbeddedTemplate);
+        parser.set_injections(Language::EmbeddedTemplate, Injection::template(Language::Ruby));
+        let tree = parser.parse("<% if x %>hi<% end %>").unwrap();
+
+        let langs = tree.layers().iter().map(|l| l.tree.language()).collect::<Vec<_>>();
+        assert_eq!(langs, vec![Language::EmbeddedTemplate, Language::Html]);
+    }
+
+    #[test]
+    #[cfg(all(feature = "lang_embedded_template", feature = "lang_html", feature = "lang_javascript"))]
+    fn test_ejs() {
+        let mut parser = LayeredParser::new(Language::EmbeddedTemplate);
+        let code = Injection::template_language("ejs").unwrap();
+        parser.set_injections(Language::EmbeddedTemplate, Injection::template(code));
+        let tree = parser.parse("<% if (x) { %><p><%= f(x) %></p><% } %>").unwrap();
+
+        let langs = tree.layers().iter().map(|l| l.tree.language()).collect::<Vec<_>>();
+        assert_eq!(langs, vec![Language::EmbeddedTemplate, Language::Html, Language::Javascript]);
+
+        let calls = tree.nodes()
+            .filter(|n| n.language() == Language::Javascript && n.kind_name() == "call_expression")
+            .map(|n| n.text())
+            .collect::<Vec<_>>();
+        assert_eq!(calls, vec!["f(x)"]);
+    }
+}
@@ -1,13 +1,43 @@
 mod parse;
 mod transform;
+mod inject;
+mod highlight;
+mod symbols;
+mod tags;
+mod outline;
+mod fold;
+mod format;
+mod trivia;
+mod cst;
+mod builder;
+mod find;
+mod lint;
+mod report;
+mod metrics;
+mod clones;
+mod fingerprint;
+mod position;
+mod encoding;
 
 pub use parse::{
     Language,
     Parser,
     Tree,
+    DetachedTree,
+    OwnedTree,
+    TextChunks,
+    Utf16Tree,
+    Utf16Node,
     Node,
     Kind,
     Child,
+    Point,
+    Range,
+    InputEdit,
+    LogType,
+    Logger,
+    ParseError,
+    CancellationFlag,
 };
 
 pub use transform::{
@@ -17,4 +47,133 @@ pub use transform::{
     Rule,
     Program,
     Transform,
-};
\ No newline at end of file
+};
+
+pub use inject::{
+    Injection,
+    Layer,
+    LayeredTree,
+    LayeredParser,
+};
+
+pub use highlight::{
+    Scope,
+    Span,
+    Format,
+    Highlighter,
+    render_ansi,
+    render_html,
+};
+
+pub use symbols::{
+    SymbolKind,
+    symbol_kind,
+    symbol_name,
+    callee_name,
+};
+
+pub use tags::{
+    Tag,
+    TaggedFile,
+    write_ctags,
+    write_etags,
+};
+
+pub use outline::Symbol;
+
+pub use fold::{
+    FoldKind,
+    FoldingRange,
+    selection_chain,
+    expand_selection,
+};
+
+pub use format::{
+    Doc,
+    FormatRule,
+    Formatter,
+};
+
+pub use trivia::{
+    Trivia,
+    Comments,
+};
+
+pub use cst::{
+    leaves,
+    Cst,
+    CstNode,
+    CstChild,
+};
+
+pub use builder::{
+    NodeBuilder,
+    TreeBuilder,
+};
+
+pub use find::{
+    TextMode,
+    TextMatch,
+    Literals,
+    Bindings,
+    Schema,
+    Finder,
+    substitute_placeholders,
+};
+
+pub use lint::{
+    Severity,
+    LintRule,
+    Diagnostic,
+    LintedFile,
+    Linter,
+    Fixed,
+    Baseline,
+};
+
+pub use report::{
+    ReportFormat,
+    write_report,
+    write_text,
+    sarif,
+    write_sarif,
+    write_checkstyle,
+    write_junit,
+};
+
+pub use metrics::{
+    Halstead,
+    FunctionMetrics,
+    MeasuredFile,
+    write_metrics_csv,
+    metrics_json,
+};
+
+pub use clones::{
+    CloneType,
+    CloneOptions,
+    CloneInstance,
+    CloneGroup,
+    CloneDetector,
+};
+
+pub use fingerprint::{
+    HashOptions,
+    Fingerprint,
+    FingerprintIndex,
+};
+
+pub use position::{
+    ColumnUnit,
+    LineIndex,
+    grapheme_boundaries,
+    column_width,
+    column_offset,
+};
+
+pub use encoding::{
+    Encoding,
+    Decoded,
+    decode,
+    declared_encoding,
+};
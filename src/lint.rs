@@ -0,0 +1,442 @@
+use crate::parse::{Parser, Tree, Node, Kind, Language, Range};
+use crate::find::{Finder, Literals, TextMode, Bindings};
+use crate::trivia::Comments;
+use crate::cst::Cst;
+use crate::position::{ColumnUnit, column_width};
+use failure::{Error, format_err};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::fs;
+
+// A comment containing this turns off lint rules for the line it's on, or for the next line if
+// it's on a line of its own. `hornbeam-ignore: a, b` only turns off rules `a` and `b`.
+const SUPPRESS: &str = "hornbeam-ignore";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+}
+
+impl FromStr for Severity {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Error> {
+        Ok(match text {
+            "error" => Severity::Error,
+            "warning" => Severity::Warning,
+            "note" => Severity::Note,
+            _ => return Err(format_err!("invalid severity '{}'", text))
+        })
+    }
+}
+
+pub struct LintRule {
+    pub id: String,
+    pub language: Language,
+    pub finder: Finder,
+    // The message and fix can mention the finder's metavariables, e.g. `$X`.
+    pub message: String,
+    pub severity: Severity,
+    // Text to replace the matched node with.
+    pub fix: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub rule: String,
+    pub severity: Severity,
+    pub message: String,
+    pub kind: Kind,
+    pub range: Range,
+    // `range` with its offsets and columns counting UTF-16 code units, as SARIF does.
+    pub utf16_range: Range,
+    pub text: String,
+    pub fix: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LintedFile {
+    pub path: String,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+pub struct Linter {
+    rules: Vec<LintRule>,
+}
+
+impl Diagnostic {
+    pub fn new(rule: &str, severity: Severity, message: String, node: &Node) -> Diagnostic {
+        Diagnostic {
+            rule: rule.to_string(),
+            severity,
+            message,
+            kind: node.kind(),
+            range: node.range(),
+            utf16_range: utf16_range(node.source(), node.range()),
+            text: node.text().to_string(),
+            fix: None,
+        }
+    }
+}
+
+fn utf16_range(text: &str, range: Range) -> Range {
+    let units = |start: usize, end: usize| column_width(&text[start..end], ColumnUnit::Utf16);
+    let mut res = range;
+    res.start_byte = units(0, range.start_byte);
+    res.end_byte = res.start_byte + units(range.start_byte, range.end_byte);
+    res.start_point.column = units(range.start_byte - range.start_point.column, range.start_byte);
+    res.end_point.column = units(range.end_byte - range.end_point.column, range.end_byte);
+    res
+}
+
+fn expand(template: &str, bindings: &Bindings) -> String {
+    let mut res = String::new();
+    let mut rest = template;
+
+    while let Some(i) = rest.find('$') {
+        res.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+
+        let len = rest.find(|c: char| !(c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')).unwrap_or(rest.len());
+        match bindings.iter().find(|(name, _)| *name == rest[..len]) {
+            Some((_, text)) if len > 0 => res.push_str(text),
+            _ => {
+                res.push('$');
+                res.push_str(&rest[..len]);
+            }
+        }
+        rest = &rest[len..];
+    }
+
+    res.push_str(rest);
+    res
+}
+
+fn strings(value: &Value, name: &str) -> Vec<String> {
+    match value.get(name) {
+        Some(Value::String(s)) => vec![s.clone()],
+        Some(Value::Array(items)) => items.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+        _ => Vec::new(),
+    }
+}
+
+impl LintRule {
+    // A rule from its config entry, e.g.
+    //
+    //   {"id": "no-print", "language": "python", "pattern": "print($X)", "not_inside": "kind:if_statement",
+    //    "message": "print of $X", "severity": "warning", "fix": "log($X)"}
+    //
+    // `pattern`, `inside`, `not_inside` and `has` are examples, or `kind:` and a kind name;
+    // `context`, `literal` and `text_match` mean the same as the CLI options, except that by
+    // default every leaf of an example has to match by text.
+    pub fn from_json(value: &Value) -> Result<LintRule, Error> {
+        let field = |name| value.get(name).and_then(Value::as_str);
+        let id = field("id").ok_or_else(|| format_err!("lint rule without an id"))?;
+        let required = |name| field(name).ok_or_else(|| format_err!("lint rule '{}' has no {}", id, name));
+
+        let language = required("language")?.parse::<Language>()?;
+        if !language.is_compiled_in() {
+            return Err(format_err!("lint rule '{}' is for {:?}, which isn't compiled in", id, language));
+        }
+
+        let literals = Literals {
+            kinds: if value.get("literal").is_some() { strings(value, "literal") } else { vec!["*".to_string()] },
+            mode: field("text_match").map_or(Ok(TextMode::Exact), str::parse)?,
+        };
+
+        let mut parser = Parser::new(language);
+        let mut finder = Finder::from_pattern(&mut parser, required("pattern")?, field("context"), &literals)?;
+        for pattern in strings(value, "inside") {
+            finder = finder.inside(Finder::from_pattern(&mut parser, &pattern, None, &literals)?);
+        }
+        for pattern in strings(value, "not_inside") {
+            finder = finder.not_inside(Finder::from_pattern(&mut parser, &pattern, None, &literals)?);
+        }
+        for pattern in strings(value, "has") {
+            finder = finder.has(Finder::from_pattern(&mut parser, &pattern, None, &literals)?);
+        }
+
+        Ok(LintRule {
+            id: id.to_string(),
+            language,
+            finder,
+            message: required("message")?.to_string(),
+            severity: field("severity").map_or(Ok(Severity::Warning), str::parse)?,
+            fix: field("fix").map(str::to_string),
+        })
+    }
+}
+
+impl Linter {
+    pub fn new(rules: Vec<LintRule>) -> Linter {
+        Linter { rules }
+    }
+
+    // A config file is an object with a list of rules, `{"rules": [...]}`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Linter, Error> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let config: Value = serde_json::from_str(&text)
+            .map_err(|e| format_err!("{}: {}", path.display(), e))?;
+        Linter::from_json(&config)
+    }
+
+    pub fn from_json(config: &Value) -> Result<Linter, Error> {
+        let rules = config.get("rules").and_then(Value::as_array)
+            .ok_or_else(|| format_err!("lint config has no list of rules"))?;
+        Ok(Linter::new(rules.iter().map(LintRule::from_json).collect::<Result<_, _>>()?))
+    }
+
+    pub fn rules(&self) -> &[LintRule] {
+        &self.rules
+    }
+
+    pub fn has_rules_for(&self, lang: Language) -> bool {
+        self.rules.iter().any(|r| r.language == lang)
+    }
+
+    // Every match of every rule for the tree's language, in order, except suppressed ones.
+    pub fn check(&self, tree: &Tree) -> Vec<Diagnostic> {
+        let rules = self.rules.iter().filter(|r| r.language == tree.language()).collect::<Vec<_>>();
+        if rules.is_empty() {
+            return Vec::new();
+        }
+
+        let suppressed = suppressions(tree);
+        let mut res = Vec::new();
+
+        for node in tree.nodes() {
+            for rule in &rules {
+                let bindings = match rule.finder.matches(&node) {
+                    Some(bindings) => bindings,
+                    None => continue,
+                };
+
+                let row = node.start_point().row;
+                if suppressed.iter().any(|(r, ids)| *r == row && (ids.is_empty() || ids.contains(&rule.id))) {
+                    continue;
+                }
+
+                let mut diagnostic = Diagnostic::new(&rule.id, rule.severity, expand(&rule.message, &bindings), &node);
+                diagnostic.fix = rule.fix.as_ref().map(|f| expand(f, &bindings));
+                res.push(diagnostic);
+            }
+        }
+
+        res
+    }
+
+    // The tree's text with the fixes of `diagnostics` applied, one at a time. A fix inside a node
+    // that another fix already replaced is skipped, and so is one that would add syntax errors.
+    pub fn fix<'d>(tree: &Tree, diagnostics: &'d [Diagnostic]) -> Result<Fixed<'d>, Error> {
+        let mut parser = Parser::new(tree.language());
+        let mut cst = Cst::new(tree);
+        let mut errors = error_count(tree);
+        let mut skipped = Vec::new();
+
+        for node in tree.nodes() {
+            let fixes = diagnostics.iter()
+                .filter(|d| d.kind == node.kind() && d.range.start_byte == node.start_byte() && d.range.end_byte == node.end_byte())
+                .filter_map(|d| d.fix.as_ref().map(|fix| (d, fix)));
+
+            for (d, fix) in fixes {
+                let mut attempt = cst.clone();
+                if !attempt.replace_text(&node, fix) {
+                    skipped.push((d, "it overlaps another fix"));
+                    continue;
+                }
+
+                let text = attempt.to_string();
+                let attempt_errors = error_count(&parser.parse(&text)?);
+                if attempt_errors > errors {
+                    skipped.push((d, "the result has syntax errors"));
+                    continue;
+                }
+
+                cst = attempt;
+                errors = attempt_errors;
+            }
+        }
+
+        Ok(Fixed { text: cst.to_string(), skipped })
+    }
+}
+
+// The text with lint fixes applied, and the diagnostics whose fixes weren't, with the reason.
+pub struct Fixed<'a> {
+    pub text: String,
+    pub skipped: Vec<(&'a Diagnostic, &'static str)>,
+}
+
+fn error_count(tree: &Tree) -> usize {
+    tree.nodes().filter(|n| n.is_error() || n.is_missing()).count()
+}
+
+// The rows with suppression comments in effect, and the rule ids they apply to (all if empty).
+fn suppressions(tree: &Tree) -> Vec<(usize, Vec<String>)> {
+    let comments = Comments::attach(tree);
+    let mut res = Vec::new();
+
+    for comment in comments.all() {
+        let text = comment.text();
+        let i = match text.find(SUPPRESS) {
+            Some(i) => i + SUPPRESS.len(),
+            None => continue,
+        };
+
+        let ids = if text[i..].starts_with(':') {
+            text[i + 1..].trim_end_matches("*/").split(',').map(|id| id.trim().to_string()).filter(|id| !id.is_empty()).collect()
+        } else {
+            Vec::new()
+        };
+
+        let row = comment.start_point().row;
+        res.push((if comments.is_trailing(comment) { row } else { row + 1 }, ids));
+    }
+
+    res
+}
+
+// Diagnostics that were already there when the baseline was written, so that only new ones are
+// reported. Entries are keyed on the file, rule and matched text rather than on positions, so
+// that they survive edits elsewhere in the file.
+#[derive(Debug, Clone, Default)]
+pub struct Baseline {
+    counts: HashMap<(String, String, String), usize>,
+}
+
+impl Baseline {
+    pub fn new(files: &[LintedFile]) -> Baseline {
+        let mut baseline = Baseline::default();
+        for file in files {
+            for d in &file.diagnostics {
+                *baseline.counts.entry((file.path.clone(), d.rule.clone(), d.text.clone())).or_insert(0) += 1;
+            }
+        }
+        baseline
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Baseline, Error> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let entries: Value = serde_json::from_str(&text)
+            .map_err(|e| format_err!("{}: {}", path.display(), e))?;
+        Baseline::from_json(&entries).map_err(|e| format_err!("{}: {}", path.display(), e))
+    }
+
+    pub fn from_json(entries: &Value) -> Result<Baseline, Error> {
+        let mut baseline = Baseline::default();
+        for entry in entries.as_array().ok_or_else(|| format_err!("baseline isn't a list"))? {
+            let field = |name| entry.get(name).and_then(Value::as_str).map(str::to_string)
+                .ok_or_else(|| format_err!("baseline entry has no {}", name));
+            let count = entry.get("count").and_then(Value::as_u64).unwrap_or(1) as usize;
+            *baseline.counts.entry((field("path")?, field("rule")?, field("text")?)).or_insert(0) += count;
+        }
+        Ok(baseline)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut entries = self.counts.iter().collect::<Vec<_>>();
+        entries.sort();
+        Value::Array(entries.into_iter()
+            .map(|((path, rule, text), count)| json!({ "path": path, "rule": rule, "text": text, "count": count }))
+            .collect())
+    }
+
+    // Drops as many diagnostics for each entry as it counts.
+    pub fn filter(&self, file: LintedFile) -> LintedFile {
+        let LintedFile { path, diagnostics } = file;
+        let mut remaining = HashMap::new();
+        let diagnostics = diagnostics.into_iter()
+            .filter(|d| {
+                let key = (path.clone(), d.rule.clone(), d.text.clone());
+                let left = remaining.entry(key.clone()).or_insert_with(|| self.counts.get(&key).cloned().unwrap_or(0));
+                if *left > 0 {
+                    *left -= 1;
+                    false
+                } else {
+                    true
+                }
+            })
+            .collect();
+        LintedFile { path, diagnostics }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint_python() {
+        let config = json!({
+            "rules": [
+                { "id": "no-print", "language": "python", "pattern": "print($X)", "message": "print of $X", "fix": "log($X)" },
+                { "id": "no-eval", "language": "python", "pattern": "eval($X)", "message": "eval",
+                  "severity": "error", "inside": "kind:function_definition" },
+            ],
+        });
+        let linter = Linter::from_json(&config).unwrap();
+
+        let text = "print(1)\neval(x)\ndef f():\n    eval(y)\n    print(2)  # hornbeam-ignore\n    # hornbeam-ignore: no-eval\n    eval(z)\n    print(3)\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let diagnostics = linter.check(&tree);
+
+        let found = diagnostics.iter()
+            .map(|d| (d.rule.as_str(), d.severity, d.message.as_str(), d.range.start_point.row))
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![
+            ("no-print", Severity::Warning, "print of 1", 0),
+            ("no-eval", Severity::Error, "eval", 3),
+            ("no-print", Severity::Warning, "print of 3", 7),
+        ]);
+        let fixed = Linter::fix(&tree, &diagnostics).unwrap();
+        assert_eq!(fixed.text, text.replace("print(1)", "log(1)").replace("print(3)", "log(3)"));
+        assert!(fixed.skipped.is_empty());
+
+        let file = LintedFile { path: "a.py".to_string(), diagnostics };
+        let mut baseline = Baseline::new(std::slice::from_ref(&file));
+        baseline.counts.retain(|(_, _, text), _| text != "print(3)");
+        let baseline = Baseline::from_json(&baseline.to_json()).unwrap();
+        let left = baseline.filter(file);
+        assert_eq!(left.diagnostics.iter().map(|d| d.text.as_str()).collect::<Vec<_>>(), vec!["print(3)"]);
+    }
+
+    #[test]
+    fn test_fix() {
+        let config = json!({
+            "rules": [
+                { "id": "f-to-g", "language": "python", "pattern": "f($X)", "message": "f", "fix": "g($X)" },
+                { "id": "broken", "language": "python", "pattern": "h($X)", "message": "h", "fix": "h($X" },
+            ],
+        });
+        let linter = Linter::from_json(&config).unwrap();
+
+        let text = "f(f(1))\nh(2)\nf(3)\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let diagnostics = linter.check(&tree);
+        let fixed = Linter::fix(&tree, &diagnostics).unwrap();
+
+        // The inner `f(1)` went away with the outer fix, so its own fix has nowhere to go.
+        assert_eq!(fixed.text, "g(f(1))\nh(2)\ng(3)\n");
+        let skipped = fixed.skipped.iter().map(|(d, why)| (d.text.as_str(), *why)).collect::<Vec<_>>();
+        assert_eq!(skipped, vec![
+            ("f(1)", "it overlaps another fix"),
+            ("h(2)", "the result has syntax errors"),
+        ]);
+    }
+}
@@ -0,0 +1,353 @@
+use crate::parse::{Language, Tree, Node, Range};
+use crate::symbols::{self, SymbolKind};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashSet};
+use std::io::{self, Write};
+
+// Node kinds that add a path through a function, each adding one to its cyclomatic complexity.
+fn branch_kinds(lang: Language) -> &'static [&'static str] {
+    match lang {
+        Language::Rust => &["if_expression", "if_let_expression", "for_expression", "while_expression", "while_let_expression", "match_arm"],
+        Language::Python => &["if_statement", "elif_clause", "for_statement", "while_statement", "except_clause", "conditional_expression", "for_in_clause", "if_clause"],
+        Language::Javascript |
+        Language::Typescript => &["if_statement", "for_statement", "for_in_statement", "for_of_statement", "while_statement", "do_statement", "switch_case", "catch_clause", "ternary_expression"],
+        Language::C => &["if_statement", "for_statement", "while_statement", "do_statement", "case_statement", "conditional_expression"],
+        Language::Cpp => &["if_statement", "for_statement", "for_range_loop", "while_statement", "do_statement", "case_statement", "catch_clause", "conditional_expression"],
+        Language::Go => &["if_statement", "for_statement", "expression_case", "type_case", "communication_case"],
+        Language::CSharp => &["if_statement", "while_statement", "do_statement", "switch_section", "catch_clause", "ternary_expression"],
+        Language::Php => &["if_statement", "else_if_clause", "for_statement", "foreach_statement", "while_statement", "do_statement", "case_statement", "catch_clause", "conditional_expression"],
+        Language::Ruby => &["if", "elsif", "unless", "while", "until", "for", "when", "rescue", "conditional", "if_modifier", "unless_modifier", "while_modifier", "until_modifier", "rescue_modifier"],
+        Language::Bash => &["if_statement", "elif_clause", "for_statement", "c_style_for_statement", "while_statement", "case_item"],
+        Language::Scala => &["if_expression", "case_clause"],
+        Language::Julia => &["if_statement", "elseif_clause", "for_statement", "while_statement", "catch_clause"],
+        Language::Ocaml => &["if_expression", "for_expression", "while_expression", "match_case"],
+        _ => &[],
+    }
+}
+
+// Node kinds whose bodies are one level deeper.
+fn nesting_kinds(lang: Language) -> &'static [&'static str] {
+    match lang {
+        Language::Rust => &["if_expression", "if_let_expression", "for_expression", "while_expression", "while_let_expression", "loop_expression", "match_expression"],
+        Language::Python => &["if_statement", "for_statement", "while_statement", "try_statement", "with_statement"],
+        Language::Javascript |
+        Language::Typescript => &["if_statement", "for_statement", "for_in_statement", "for_of_statement", "while_statement", "do_statement", "switch_statement", "try_statement", "with_statement"],
+        Language::C => &["if_statement", "for_statement", "while_statement", "do_statement", "switch_statement"],
+        Language::Cpp => &["if_statement", "for_statement", "for_range_loop", "while_statement", "do_statement", "switch_statement", "try_statement"],
+        Language::Go => &["if_statement", "for_statement", "expression_switch_statement", "type_switch_statement", "select_statement"],
+        Language::CSharp => &["if_statement", "while_statement", "do_statement", "switch_statement", "try_statement"],
+        Language::Php => &["if_statement", "for_statement", "foreach_statement", "while_statement", "do_statement", "switch_statement", "try_statement"],
+        Language::Ruby => &["if", "unless", "while", "until", "for", "case", "begin"],
+        Language::Bash => &["if_statement", "for_statement", "c_style_for_statement", "while_statement", "case_statement"],
+        Language::Scala => &["if_expression", "match_expression", "try_expression"],
+        Language::Julia => &["if_statement", "for_statement", "while_statement", "try_statement"],
+        Language::Ocaml => &["if_expression", "for_expression", "while_expression", "match_expression", "try_expression"],
+        _ => &[],
+    }
+}
+
+// Node kinds holding a function's parameters.
+fn parameter_kinds(lang: Language) -> &'static [&'static str] {
+    match lang {
+        Language::Rust => &["parameters", "closure_parameters"],
+        Language::Python => &["parameters", "lambda_parameters"],
+        Language::Javascript |
+        Language::Typescript |
+        Language::Php => &["formal_parameters"],
+        Language::C |
+        Language::Cpp |
+        Language::Go |
+        Language::CSharp |
+        Language::Julia => &["parameter_list"],
+        Language::Ruby => &["method_parameters", "lambda_parameters", "block_parameters"],
+        Language::Scala => &["parameters"],
+        _ => &[],
+    }
+}
+
+// Operators that short-circuit, which branch just like an `if`.
+const LOGICAL_OPERATORS: &[&str] = &["&&", "||", "and", "or", "??"];
+
+fn is_comment(node: &Node) -> bool {
+    node.kind_name().contains("comment")
+}
+
+fn is_function(node: &Node) -> bool {
+    // Some grammars have a keyword token with the same name as the node, like `function`.
+    if !node.is_named() {
+        return false;
+    }
+    matches!(symbols::definition_kind(node), Some(SymbolKind::Function) | Some(SymbolKind::Method))
+}
+
+// Halstead's counts, taking unnamed tokens (keywords and punctuation) as operators and named
+// leaves (identifiers and literals) as operands.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Halstead {
+    pub distinct_operators: usize,
+    pub distinct_operands: usize,
+    pub total_operators: usize,
+    pub total_operands: usize,
+}
+
+impl Halstead {
+    pub fn vocabulary(&self) -> usize {
+        self.distinct_operators + self.distinct_operands
+    }
+
+    pub fn length(&self) -> usize {
+        self.total_operators + self.total_operands
+    }
+
+    pub fn volume(&self) -> f64 {
+        if self.vocabulary() == 0 {
+            return 0.0;
+        }
+        self.length() as f64 * (self.vocabulary() as f64).log2()
+    }
+
+    pub fn difficulty(&self) -> f64 {
+        if self.distinct_operands == 0 {
+            return 0.0;
+        }
+        self.distinct_operators as f64 / 2.0 * self.total_operands as f64 / self.distinct_operands as f64
+    }
+
+    pub fn effort(&self) -> f64 {
+        self.difficulty() * self.volume()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionMetrics {
+    pub name: String,
+    pub range: Range,
+    // Lines with code on them, and lines with comments; a line can be both.
+    pub lines: usize,
+    pub comment_lines: usize,
+    pub complexity: usize,
+    pub max_nesting: usize,
+    pub parameters: usize,
+    pub halstead: Halstead,
+}
+
+#[derive(Debug, Clone)]
+pub struct MeasuredFile {
+    pub path: String,
+    pub functions: Vec<FunctionMetrics>,
+}
+
+// What a function's body contributes, leaving out the bodies of functions nested in it, which
+// are measured on their own.
+#[derive(Default)]
+struct Counts<'a> {
+    branches: usize,
+    max_nesting: usize,
+    operators: Vec<&'a str>,
+    operands: Vec<&'a str>,
+}
+
+impl<'a> Counts<'a> {
+    fn add(&mut self, node: &Node<'a>, depth: usize) {
+        let lang = node.language();
+        if branch_kinds(lang).contains(&node.kind_name()) {
+            self.branches += 1;
+        }
+
+        let depth = if nesting_kinds(lang).contains(&node.kind_name()) && !is_else_if(node) {
+            self.max_nesting = self.max_nesting.max(depth + 1);
+            depth + 1
+        } else {
+            depth
+        };
+
+        if node.nodes().next().is_none() {
+            if is_comment(node) {
+                return;
+            }
+            if node.is_named() {
+                self.operands.push(node.text());
+            } else {
+                if LOGICAL_OPERATORS.contains(&node.text()) {
+                    self.branches += 1;
+                }
+                self.operators.push(node.text());
+            }
+        }
+
+        for child in node.nodes().filter(|c| !is_function(c)) {
+            self.add(&child, depth);
+        }
+    }
+}
+
+// An `if` that is the `else` branch of another `if` is at the same level as it.
+fn is_else_if(node: &Node) -> bool {
+    let parent = match node.parent() {
+        Some(parent) => parent,
+        None => return false,
+    };
+    if parent.kind_name().starts_with("else") {
+        return true;
+    }
+
+    let siblings = parent.nodes().collect::<Vec<_>>();
+    match siblings.iter().position(|s| s.start_byte() == node.start_byte() && s.kind() == node.kind()) {
+        Some(i) if i > 0 => siblings[i - 1].text() == "else",
+        _ => false,
+    }
+}
+
+fn count_rows(node: &Node, code: &mut BTreeSet<usize>, comments: &mut BTreeSet<usize>) {
+    if node.nodes().next().is_some() {
+        for child in node.nodes() {
+            count_rows(&child, code, comments);
+        }
+        return;
+    }
+
+    let rows = node.start_point().row..=node.end_point().row;
+    if is_comment(node) {
+        comments.extend(rows);
+    } else {
+        code.extend(rows);
+    }
+}
+
+fn parameter_count(node: &Node) -> usize {
+    let kinds = parameter_kinds(node.language());
+    let name_end = symbols::symbol_name(node).map_or(node.start_byte(), |n| n.end_byte());
+
+    let mut stack = node.nodes().collect::<Vec<_>>();
+    stack.reverse();
+    while let Some(n) = stack.pop() {
+        if kinds.contains(&n.kind_name()) && n.start_byte() >= name_end {
+            return n.nodes().filter(|p| p.is_named() && !is_comment(p)).count();
+        }
+        if !is_function(&n) {
+            let mut children = n.nodes().collect::<Vec<_>>();
+            children.reverse();
+            stack.extend(children);
+        }
+    }
+    0
+}
+
+impl FunctionMetrics {
+    // Metrics for every function and method in the tree, in order.
+    pub fn collect(tree: &Tree) -> Vec<FunctionMetrics> {
+        tree.nodes().filter(is_function).map(|n| FunctionMetrics::of(&n)).collect()
+    }
+
+    pub fn of(node: &Node) -> FunctionMetrics {
+        let mut counts = Counts::default();
+        for child in node.nodes().filter(|c| !is_function(c)) {
+            counts.add(&child, 0);
+        }
+
+        let mut code = BTreeSet::new();
+        let mut comments = BTreeSet::new();
+        count_rows(node, &mut code, &mut comments);
+
+        let distinct = |items: &[&str]| items.iter().collect::<HashSet<_>>().len();
+        FunctionMetrics {
+            name: symbols::symbol_name(node).map_or_else(String::new, |n| n.text().to_string()),
+            range: node.range(),
+            lines: code.len(),
+            comment_lines: comments.len(),
+            complexity: 1 + counts.branches,
+            max_nesting: counts.max_nesting,
+            parameters: parameter_count(node),
+            halstead: Halstead {
+                distinct_operators: distinct(&counts.operators),
+                distinct_operands: distinct(&counts.operands),
+                total_operators: counts.operators.len(),
+                total_operands: counts.operands.len(),
+            },
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let h = &self.halstead;
+        json!({
+            "name": self.name,
+            "line": self.range.start_point.row + 1,
+            "endLine": self.range.end_point.row + 1,
+            "lines": self.lines,
+            "commentLines": self.comment_lines,
+            "complexity": self.complexity,
+            "maxNesting": self.max_nesting,
+            "parameters": self.parameters,
+            "halstead": {
+                "distinctOperators": h.distinct_operators,
+                "distinctOperands": h.distinct_operands,
+                "totalOperators": h.total_operators,
+                "totalOperands": h.total_operands,
+                "volume": h.volume(),
+                "difficulty": h.difficulty(),
+                "effort": h.effort(),
+            },
+        })
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+pub fn write_metrics_csv<W: Write>(out: &mut W, files: &[MeasuredFile]) -> io::Result<()> {
+    writeln!(out, "path,function,line,end_line,lines,comment_lines,complexity,max_nesting,parameters,\
+        distinct_operators,distinct_operands,total_operators,total_operands,volume,difficulty,effort")?;
+    for file in files {
+        for f in &file.functions {
+            let h = &f.halstead;
+            writeln!(out, "{},{},{},{},{},{},{},{},{},{},{},{},{},{:.2},{:.2},{:.2}",
+                csv_field(&file.path), csv_field(&f.name), f.range.start_point.row + 1, f.range.end_point.row + 1,
+                f.lines, f.comment_lines, f.complexity, f.max_nesting, f.parameters,
+                h.distinct_operators, h.distinct_operands, h.total_operators, h.total_operands,
+                h.volume(), h.difficulty(), h.effort())?;
+        }
+    }
+    Ok(())
+}
+
+pub fn metrics_json(files: &[MeasuredFile]) -> Value {
+    Value::Array(files.iter()
+        .map(|f| json!({ "path": f.path, "functions": f.functions.iter().map(FunctionMetrics::to_json).collect::<Vec<_>>() }))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::Parser;
+
+    #[test]
+    fn test_python_metrics() {
+        let text = "def f(a, b=1):\n    # check\n    if a and b:\n        for x in a:\n            pass\n    elif b:\n        def g():\n            if x:\n                pass\n    return a\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let metrics = FunctionMetrics::collect(&tree);
+
+        let summary = metrics.iter()
+            .map(|m| (m.name.as_str(), m.lines, m.comment_lines, m.complexity, m.max_nesting, m.parameters))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            ("f", 9, 1, 5, 2, 2),
+            ("g", 3, 0, 2, 1, 0),
+        ]);
+
+        let h = &metrics[1].halstead;
+        assert_eq!((h.distinct_operators, h.total_operators, h.distinct_operands, h.total_operands), (6, 7, 2, 2));
+        assert_eq!(h.vocabulary(), 8);
+        assert!((h.volume() - 27.0).abs() < 1e-9);
+
+        let mut out = Vec::new();
+        write_metrics_csv(&mut out, &[MeasuredFile { path: "a.py".to_string(), functions: metrics }]).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.lines().nth(2).unwrap().starts_with("a.py,g,7,9,3,0,2,1,0,6,2,7,2,27.00,"));
+    }
+}
@@ -0,0 +1,117 @@
+use crate::parse::{Tree, Node, Point, Range};
+use crate::position::{LineIndex, ColumnUnit};
+use crate::symbols::{self, SymbolKind};
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    // The whole definition, e.g. a function including its body.
+    pub range: Range,
+    // Just the name, which is what an editor should select when jumping to the symbol.
+    pub selection_range: Range,
+    pub children: Vec<Symbol>,
+}
+
+// LSP positions count UTF-16 code units, while points count bytes.
+fn point_json(p: Point, lines: &LineIndex) -> Value {
+    json!({ "line": p.row, "character": lines.column(p, ColumnUnit::Utf16) })
+}
+
+fn range_json(r: &Range, lines: &LineIndex) -> Value {
+    json!({ "start": point_json(r.start_point, lines), "end": point_json(r.end_point, lines) })
+}
+
+impl Symbol {
+    pub fn outline(tree: &Tree) -> Vec<Symbol> {
+        let mut symbols = Vec::new();
+        Symbol::collect(&tree.root(), &mut symbols);
+        symbols
+    }
+
+    fn collect(node: &Node, out: &mut Vec<Symbol>) {
+        for child in node.nodes() {
+            let symbol = symbols::symbol_kind(&child)
+                .and_then(|kind| symbols::symbol_name(&child).map(|name| (kind, name)));
+
+            if let Some((kind, name)) = symbol {
+                let mut children = Vec::new();
+                Symbol::collect(&child, &mut children);
+                out.push(Symbol {
+                    name: name.text().to_string(),
+                    kind,
+                    range: child.range(),
+                    selection_range: name.range(),
+                    children,
+                });
+            } else {
+                Symbol::collect(&child, out);
+            }
+        }
+    }
+
+    // The symbols enclosing `point`, outermost first.
+    pub fn breadcrumbs(symbols: &[Symbol], point: Point) -> Vec<&Symbol> {
+        let mut res = Vec::new();
+        let mut level = symbols;
+        while let Some(s) = level.iter().find(|s| s.range.start_point <= point && point < s.range.end_point) {
+            res.push(s);
+            level = &s.children;
+        }
+        res
+    }
+
+    // Shaped like an LSP `DocumentSymbol`, except that `kind` is a name rather than a number.
+    // `lines` indexes the text the symbol was found in.
+    pub fn to_json(&self, lines: &LineIndex) -> Value {
+        json!({
+            "name": self.name,
+            "kind": self.kind.name(),
+            "range": range_json(&self.range, lines),
+            "selectionRange": range_json(&self.selection_range, lines),
+            "children": self.children.iter().map(|c| c.to_json(lines)).collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{Parser, Language};
+
+    #[test]
+    fn test_python_outline() {
+        let text = "class A:\n    def f(self):\n        pass\n    def g(self):\n        pass\n\ndef h():\n    pass\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let outline = Symbol::outline(&tree);
+
+        let names = outline.iter()
+            .map(|s| (s.name.as_str(), s.kind, s.children.iter().map(|c| c.name.as_str()).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![
+            ("A", SymbolKind::Class, vec!["f", "g"]),
+            ("h", SymbolKind::Function, vec![]),
+        ]);
+
+        let g = &outline[0].children[1];
+        assert_eq!(g.kind, SymbolKind::Method);
+        assert_eq!(g.selection_range.start_point, Point::new(3, 8));
+
+        let crumbs = Symbol::breadcrumbs(&outline, Point::new(4, 9));
+        assert_eq!(crumbs.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(), vec!["A", "g"]);
+    }
+
+    #[test]
+    fn test_json_columns() {
+        let text = "class A:\n    def f(self): return 'é😀'\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let outline = Symbol::outline(&tree);
+
+        // The string is 6 bytes long but only 3 UTF-16 code units.
+        let json = outline[0].to_json(&LineIndex::new(text));
+        assert_eq!(outline[0].range.end_point, Point::new(1, 32));
+        assert_eq!(json["range"]["end"], json!({ "line": 1, "character": 29 }));
+        assert_eq!(json["children"][0]["selectionRange"]["start"], json!({ "line": 1, "character": 8 }));
+    }
+}
@@ -1,7 +1,14 @@
 use std::str::FromStr;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use tree_sitter as ts;
+pub use tree_sitter::{Point, Range, InputEdit, LogType};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
 use failure::{Error, format_err};
 
 extern "C" {
@@ -26,6 +33,9 @@ extern "C" {
     #[cfg(feature = "lang_css")]
     fn tree_sitter_css() -> ts::Language;
     
+    #[cfg(feature = "lang_embedded_template")]
+    fn tree_sitter_embedded_template() -> ts::Language;
+    
     #[cfg(feature = "lang_go")]
     fn tree_sitter_go() -> ts::Language;
     
@@ -72,6 +82,7 @@ pub enum Language {
     C,
     Cpp,
     Css,
+    EmbeddedTemplate,
     Go,
     Html,
     Ocaml,
@@ -99,6 +110,7 @@ impl FromStr for Language {
             "c" => Language::C,
             "cpp" => Language::Cpp,
             "css" => Language::Css,
+            "embedded-template" => Language::EmbeddedTemplate,
             "go" => Language::Go,
             "html" => Language::Html,
             "ocaml" => Language::Ocaml,
@@ -126,6 +138,8 @@ impl Language {
             "c" => Language::C,
             "cpp" => Language::Cpp,
             "css" => Language::Css,
+            "erb" => Language::EmbeddedTemplate,
+            "ejs" => Language::EmbeddedTemplate,
             "go" => Language::Go,
             "html" => Language::Html,
             "ocaml" => Language::Ocaml,
@@ -141,208 +155,34 @@ impl Language {
             _ => return Err(format_err!("invalid language extension '{}'", ext))
         })
     }
-}
-
-pub struct LanguageInfo {
-    kinds_by_name: HashMap<&'static str, Kind>,
-    kinds_by_id: Vec<&'static str>,
-}
-
-pub struct Parser {
-    pub info: LanguageInfo,
-    inner: ts::Parser,
-}
-
-pub struct Tree<'a> {
-    inner: ts::Tree,
-    text: &'a str,
-}
-
-#[derive(Clone)]
-pub struct Node<'a> {
-    inner: ts::Node<'a>,
-    text: &'a str,
-}
-
-#[derive(Clone)]
-pub enum Child<'a> {
-    Node(Node<'a>),
-    Text(&'a str),
-}
-
-#[derive(Eq, PartialEq, Hash, Debug, Copy, Clone)]
-pub struct Kind(u16);
-
-impl<'a> Tree<'a> {
-    pub fn root(&'a self) -> Node<'a> {
-        Node {
-            inner: self.inner.root_node(),
-            text: self.text,
-        }
-    }
-
-    pub fn nodes(&'a self) -> impl Iterator<Item=Node<'a>> {
-        AllWalker {
-            walker: self.inner.walk(),
-            descend: true,
-            text: self.text,
-        }
-    }
-}
-
-impl<'a> fmt::Debug for Tree<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.inner.root_node().to_sexp())
-    }
-}
-
-impl<'a> fmt::Debug for Node<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.inner.to_sexp())
-    }
-}
-
-impl<'a> Node<'a> {
-    pub fn kind(&self) -> Kind {
-        Kind(self.inner.kind_id())
-    }
-
-    pub fn nodes(&self) -> impl Iterator<Item=Node<'a>> {
-        let text = self.text;
-        self.inner.children().map(move |inner| Node {
-            inner,
-            text,
-        })
-    }
-
-    pub fn children(&self) -> impl Iterator<Item=Child<'a>> {
-        Children::new(self.text, self.inner.start_byte(), self.inner.end_byte(), self.inner.children())
-    }
-
-    pub fn text(&self) -> &'a str {
-        self.inner.utf8_text(self.text.as_bytes()).unwrap()
-    }
-}
-
-struct Children<'a, It: Iterator<Item=ts::Node<'a>>> {
-    text: &'a str,
-    it: It,
-    offset: usize,
-    end: usize,
-    buffer: Option<ts::Node<'a>>,
-}
-
-impl<'a, It: Iterator<Item=ts::Node<'a>>> Children<'a, It> {
-    fn new(text: &'a str, start: usize, end: usize, it: It) -> Children<'a, It> {
-        Children {
-            text,
-            it,
-            offset: start,
-            end,
-            buffer: None,
-        }
-    }
-}
-
-impl<'a, It: Iterator<Item=ts::Node<'a>>> Iterator for Children<'a, It> {
-    type Item = Child<'a>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(node) = self.buffer.take() {
-            return Some(Child::Node(Node {
-                text: self.text,
-                inner: node,
-            }));
-        }
-
-        if let Some(node) = self.it.next() {
-            let start = node.start_byte();
-            assert!(start <= self.end);
-            if start > self.offset {
-                self.buffer = Some(node);
-                let offset = self.offset;
-                self.offset = node.end_byte();
-                Some(Child::Text(&self.text[offset..start]))
-            } else {
-                self.offset = node.end_byte();
-                Some(Child::Node(Node {
-                    text: self.text,
-                    inner: node,
-                }))
-            }
-        } else if self.offset < self.end {
-            let offset = self.offset;
-            self.offset = self.end;
-            Some(Child::Text(&self.text[offset..self.end]))
-        } else {
-            None
-        }
-    }
-}
-
-struct AllWalker<'a> {
-    walker: ts::TreeCursor<'a>,
-    descend: bool,
-    text: &'a str,
-}
-
-impl<'a> Iterator for AllWalker<'a> {
-    type Item = Node<'a>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let n = Node {
-            inner: self.walker.node(),
-            text: self.text,
-        };
-        if self.descend && self.walker.goto_first_child() {
-            Some(n)
-        } else {
-            loop {
-                if self.walker.goto_next_sibling() {
-                    self.descend = true;
-                    return Some(n);
-                }
-
-                if !self.walker.goto_parent() {
-                    return None;
-                }
-            }
-        }
-    }
-}
-
-impl LanguageInfo {
-    fn new(lang: ts::Language) -> LanguageInfo {
-        let mut kinds_by_name = HashMap::new();
-        let mut kinds_by_id = Vec::new();
-
-        for k in 0..lang.node_kind_count() {
-            let name = lang.node_kind_for_id(k as u16);
-            kinds_by_id.push(name);
-            if lang.node_kind_is_named(k as u16) {
-                // TODO: deal with duplicate names (they exist!?!?!)
-                kinds_by_name.insert(name, Kind(k as u16));
-            }
-        }
 
-        LanguageInfo {
-            kinds_by_name,
-            kinds_by_id,
+    pub fn is_compiled_in(self) -> bool {
+        match self {
+            Language::Javascript => cfg!(feature = "lang_javascript"),
+            Language::Python => cfg!(feature = "lang_python"),
+            Language::Rust => cfg!(feature = "lang_rust"),
+            Language::Bash => cfg!(feature = "lang_bash"),
+            Language::C => cfg!(feature = "lang_c"),
+            Language::Cpp => cfg!(feature = "lang_cpp"),
+            Language::Css => cfg!(feature = "lang_css"),
+            Language::EmbeddedTemplate => cfg!(feature = "lang_embedded_template"),
+            Language::Go => cfg!(feature = "lang_go"),
+            Language::Html => cfg!(feature = "lang_html"),
+            Language::Ocaml => cfg!(feature = "lang_ocaml"),
+            Language::Php => cfg!(feature = "lang_php"),
+            Language::Ruby => cfg!(feature = "lang_ruby"),
+            Language::Typescript => cfg!(feature = "lang_typescript"),
+            Language::Agda => cfg!(feature = "lang_agda"),
+            Language::CSharp => cfg!(feature = "lang_csharp"),
+            Language::Haskell => cfg!(feature = "lang_haskell"),
+            Language::Java => cfg!(feature = "lang_java"),
+            Language::Julia => cfg!(feature = "lang_julia"),
+            Language::Scala => cfg!(feature = "lang_scala"),
         }
     }
 
-    pub fn kind_from_name(&self, name: &str) -> Option<Kind> {
-        self.kinds_by_name.get(name).cloned()
-    }
-
-    pub fn kind_names(&self) -> &[&str] {
-        &self.kinds_by_id
-    }
-}
-
-impl Parser {
-    pub fn new(lang_id: Language) -> Parser {
-        let lang = match lang_id {
+    pub(crate) fn ts_language(self) -> ts::Language {
+        match self {
             Language::Javascript => {
                 #[cfg(feature = "lang_javascript")]
                 unsafe { tree_sitter_javascript() }
@@ -385,6 +225,12 @@ impl Parser {
                 #[cfg(not(feature = "lang_css"))]
                 panic!("support for {} not compiled in", "css");
             }
+            Language::EmbeddedTemplate => {
+                #[cfg(feature = "lang_embedded_template")]
+                unsafe { tree_sitter_embedded_template() }
+                #[cfg(not(feature = "lang_embedded_template"))]
+                panic!("support for {} not compiled in", "embedded-template");
+            }
             Language::Go => {
                 #[cfg(feature = "lang_go")]
                 unsafe { tree_sitter_go() }
@@ -457,21 +303,909 @@ impl Parser {
                 #[cfg(not(feature = "lang_scala"))]
                 panic!("support for {} not compiled in", "scala");
             }
-        };
+        }
+    }
+}
 
-        let mut inner = ts::Parser::new();
-        inner.set_language(lang).unwrap();
+pub struct LanguageInfo {
+    kinds_by_name: HashMap<&'static str, Kind>,
+    kinds_by_id: Vec<&'static str>,
+}
 
-        Parser {
-            info: LanguageInfo::new(lang),
-            inner,
+pub struct Parser {
+    pub info: LanguageInfo,
+    lang: Language,
+    inner: ts::Parser,
+    // Kept here so that the flag outlives the parser's pointer to it.
+    cancellation_flag: Option<CancellationFlag>,
+}
+
+pub type Logger = Box<dyn FnMut(LogType, &str)>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParseError {
+    TimedOut,
+    Cancelled,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::TimedOut => write!(f, "parse timed out"),
+            ParseError::Cancelled => write!(f, "parse was cancelled"),
         }
     }
+}
 
-    pub fn parse<'a>(&mut self, text: &'a str) -> Tree<'a> {
-        Tree {
-            inner: self.inner.parse(text, None).unwrap(),
-            text,
+impl std::error::Error for ParseError {}
+
+// A flag that stops any parse using it, from any thread. It stays set until it is reset.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationFlag(Arc<AtomicUsize>);
+
+impl CancellationFlag {
+    pub fn new() -> CancellationFlag {
+        CancellationFlag::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(1, Ordering::SeqCst);
+    }
+
+    pub fn reset(&self) {
+        self.0.store(0, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst) != 0
+    }
+}
+
+// Text kept in pieces, such as a rope or an editor's buffer. `chunk_at` returns the rest of the
+// piece that byte `offset` is in, or nothing at the end of the text. Pieces may split characters.
+pub trait TextChunks {
+    fn chunk_at(&self, offset: usize) -> &[u8];
+}
+
+impl TextChunks for str {
+    fn chunk_at(&self, offset: usize) -> &[u8] {
+        &self.as_bytes()[offset.min(self.len())..]
+    }
+}
+
+#[cfg(feature = "ropey")]
+impl TextChunks for ropey::Rope {
+    fn chunk_at(&self, offset: usize) -> &[u8] {
+        if offset >= self.len_bytes() {
+            return &[];
         }
+        let (chunk, start, _, _) = self.chunk_at_byte(offset);
+        &chunk.as_bytes()[offset - start..]
     }
-}
\ No newline at end of file
+}
+
+pub struct Tree<'a> {
+    inner: ts::Tree,
+    lang: Language,
+    text: &'a str,
+}
+
+// A tree that has been separated from its source text, so that it can be kept while the text is
+// edited and then handed back to `Parser::reparse`.
+pub struct DetachedTree {
+    inner: ts::Tree,
+    lang: Language,
+}
+
+// A tree that owns its text, so that it can be cached, sent to another thread, or kept for as
+// long as a document is open. Clones share the text and the tree's nodes.
+#[derive(Clone)]
+pub struct OwnedTree {
+    inner: ts::Tree,
+    lang: Language,
+    text: Arc<str>,
+}
+
+// A tree parsed from UTF-16 text, whose nodes' offsets and columns count UTF-16 code units.
+pub struct Utf16Tree<'a> {
+    inner: ts::Tree,
+    lang: Language,
+    text: &'a [u16],
+}
+
+#[derive(Clone)]
+pub struct Utf16Node<'a> {
+    inner: ts::Node<'a>,
+    lang: Language,
+    text: &'a [u16],
+}
+
+#[derive(Clone)]
+pub struct Node<'a> {
+    inner: ts::Node<'a>,
+    lang: Language,
+    text: &'a str,
+}
+
+#[derive(Clone)]
+pub enum Child<'a> {
+    Node(Node<'a>),
+    Text(&'a str),
+}
+
+#[derive(Eq, PartialEq, Hash, Debug, Copy, Clone)]
+pub struct Kind(u16);
+
+impl<'a> Tree<'a> {
+    pub fn root(&'a self) -> Node<'a> {
+        Node {
+            inner: self.inner.root_node(),
+            lang: self.lang,
+            text: self.text,
+        }
+    }
+
+    pub fn nodes(&'a self) -> impl Iterator<Item=Node<'a>> {
+        let (lang, text) = (self.lang, self.text);
+        AllWalker::new(self.inner.walk()).map(move |inner| Node { inner, lang, text })
+    }
+
+    pub fn language(&self) -> Language {
+        self.lang
+    }
+
+    pub fn text(&self) -> &'a str {
+        self.text
+    }
+
+    pub(crate) fn ts_tree(&self) -> &ts::Tree {
+        &self.inner
+    }
+
+    pub fn detach(self) -> DetachedTree {
+        DetachedTree {
+            inner: self.inner,
+            lang: self.lang,
+        }
+    }
+
+    pub fn into_owned(self) -> OwnedTree {
+        OwnedTree {
+            inner: self.inner,
+            lang: self.lang,
+            text: Arc::from(self.text),
+        }
+    }
+}
+
+impl OwnedTree {
+    pub fn root(&self) -> Node<'_> {
+        Node {
+            inner: self.inner.root_node(),
+            lang: self.lang,
+            text: &self.text,
+        }
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item=Node<'_>> {
+        let (lang, text) = (self.lang, &*self.text);
+        AllWalker::new(self.inner.walk()).map(move |inner| Node { inner, lang, text })
+    }
+
+    pub fn language(&self) -> Language {
+        self.lang
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn shared_text(&self) -> Arc<str> {
+        self.text.clone()
+    }
+
+    // A borrowed view, for the functions that take a `&Tree`.
+    pub fn tree(&self) -> Tree<'_> {
+        Tree {
+            inner: self.inner.clone(),
+            lang: self.lang,
+            text: &self.text,
+        }
+    }
+
+    // A copy of the tree to edit and pass to `Parser::reparse_owned` along with the edited text.
+    pub fn detach(&self) -> DetachedTree {
+        DetachedTree {
+            inner: self.inner.clone(),
+            lang: self.lang,
+        }
+    }
+}
+
+impl DetachedTree {
+    pub fn language(&self) -> Language {
+        self.lang
+    }
+
+    pub fn edit(&mut self, edit: &InputEdit) {
+        self.inner.edit(edit);
+    }
+
+    // `text` must be the text the tree was parsed (or edited) to describe.
+    pub fn attach<'a>(&self, text: &'a str) -> Tree<'a> {
+        Tree {
+            inner: self.inner.clone(),
+            lang: self.lang,
+            text,
+        }
+    }
+}
+
+impl<'a> fmt::Debug for Tree<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.inner.root_node().to_sexp())
+    }
+}
+
+impl fmt::Debug for OwnedTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.inner.root_node().to_sexp())
+    }
+}
+
+impl<'a> fmt::Debug for Utf16Tree<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.inner.root_node().to_sexp())
+    }
+}
+
+impl<'a> fmt::Debug for Utf16Node<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_named() {
+            write!(f, "{}", self.inner.to_sexp())
+        } else {
+            write!(f, "{:?}", self.kind_name())
+        }
+    }
+}
+
+impl<'a> fmt::Debug for Node<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The runtime's s-expressions of anonymous nodes aren't valid UTF-8.
+        if self.is_named() {
+            write!(f, "{}", self.inner.to_sexp())
+        } else {
+            write!(f, "{:?}", self.kind_name())
+        }
+    }
+}
+
+impl<'a> Node<'a> {
+    pub fn kind(&self) -> Kind {
+        Kind(self.inner.kind_id())
+    }
+
+    pub fn kind_name(&self) -> &'static str {
+        self.inner.kind()
+    }
+
+    pub fn language(&self) -> Language {
+        self.lang
+    }
+
+    pub fn parent(&self) -> Option<Node<'a>> {
+        self.inner.parent().map(|inner| Node {
+            inner,
+            lang: self.lang,
+            text: self.text,
+        })
+    }
+
+    pub fn prev_sibling(&self) -> Option<Node<'a>> {
+        self.inner.prev_sibling().map(|inner| Node {
+            inner,
+            lang: self.lang,
+            text: self.text,
+        })
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item=Node<'a>> {
+        let lang = self.lang;
+        let text = self.text;
+        self.inner.children().map(move |inner| Node {
+            inner,
+            lang,
+            text,
+        })
+    }
+
+    pub fn children(&self) -> impl Iterator<Item=Child<'a>> {
+        Children::new(self.lang, self.text, self.inner.start_byte(), self.inner.end_byte(), self.inner.children())
+    }
+
+    pub fn text(&self) -> &'a str {
+        self.inner.utf8_text(self.text.as_bytes()).unwrap()
+    }
+
+    // The text of the whole tree.
+    pub(crate) fn source(&self) -> &'a str {
+        self.text
+    }
+
+    pub fn start_byte(&self) -> usize {
+        self.inner.start_byte()
+    }
+
+    pub fn end_byte(&self) -> usize {
+        self.inner.end_byte()
+    }
+
+    pub fn start_point(&self) -> Point {
+        self.inner.start_position()
+    }
+
+    pub fn end_point(&self) -> Point {
+        self.inner.end_position()
+    }
+
+    pub fn range(&self) -> Range {
+        self.inner.range()
+    }
+
+    pub fn is_named(&self) -> bool {
+        self.inner.is_named()
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.inner.is_error()
+    }
+
+    pub fn is_missing(&self) -> bool {
+        self.inner.is_missing()
+    }
+
+    pub fn has_error(&self) -> bool {
+        self.inner.has_error()
+    }
+
+    pub fn descendant_for_byte_range(&self, start: usize, end: usize) -> Option<Node<'a>> {
+        self.inner.descendant_for_byte_range(start, end).map(|inner| Node {
+            inner,
+            lang: self.lang,
+            text: self.text,
+        })
+    }
+
+    pub fn descendant_for_point_range(&self, start: Point, end: Point) -> Option<Node<'a>> {
+        self.inner.descendant_for_point_range(start, end).map(|inner| Node {
+            inner,
+            lang: self.lang,
+            text: self.text,
+        })
+    }
+}
+
+impl<'a> Utf16Tree<'a> {
+    pub fn root(&'a self) -> Utf16Node<'a> {
+        Utf16Node {
+            inner: self.inner.root_node(),
+            lang: self.lang,
+            text: self.text,
+        }
+    }
+
+    pub fn nodes(&'a self) -> impl Iterator<Item=Utf16Node<'a>> {
+        let (lang, text) = (self.lang, self.text);
+        AllWalker::new(self.inner.walk()).map(move |inner| Utf16Node { inner, lang, text })
+    }
+
+    pub fn language(&self) -> Language {
+        self.lang
+    }
+
+    pub fn text(&self) -> &'a [u16] {
+        self.text
+    }
+
+    // Edits to the detached tree must count bytes of UTF-16, i.e. two per code unit.
+    pub fn detach(self) -> DetachedTree {
+        DetachedTree {
+            inner: self.inner,
+            lang: self.lang,
+        }
+    }
+}
+
+// The runtime counts bytes even in UTF-16 text.
+fn utf16_point(p: Point) -> Point {
+    Point::new(p.row, p.column / 2)
+}
+
+impl<'a> Utf16Node<'a> {
+    pub fn kind(&self) -> Kind {
+        Kind(self.inner.kind_id())
+    }
+
+    pub fn kind_name(&self) -> &'static str {
+        self.inner.kind()
+    }
+
+    pub fn language(&self) -> Language {
+        self.lang
+    }
+
+    pub fn parent(&self) -> Option<Utf16Node<'a>> {
+        self.inner.parent().map(|inner| Utf16Node {
+            inner,
+            lang: self.lang,
+            text: self.text,
+        })
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item=Utf16Node<'a>> {
+        let (lang, text) = (self.lang, self.text);
+        self.inner.children().map(move |inner| Utf16Node { inner, lang, text })
+    }
+
+    pub fn text(&self) -> &'a [u16] {
+        &self.text[self.start()..self.end()]
+    }
+
+    // Offsets in code units.
+    pub fn start(&self) -> usize {
+        self.inner.start_byte() / 2
+    }
+
+    pub fn end(&self) -> usize {
+        self.inner.end_byte() / 2
+    }
+
+    pub fn start_point(&self) -> Point {
+        utf16_point(self.inner.start_position())
+    }
+
+    pub fn end_point(&self) -> Point {
+        utf16_point(self.inner.end_position())
+    }
+
+    pub fn is_named(&self) -> bool {
+        self.inner.is_named()
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.inner.is_error()
+    }
+
+    pub fn is_missing(&self) -> bool {
+        self.inner.is_missing()
+    }
+
+    pub fn has_error(&self) -> bool {
+        self.inner.has_error()
+    }
+}
+
+struct Children<'a, It: Iterator<Item=ts::Node<'a>>> {
+    lang: Language,
+    text: &'a str,
+    it: It,
+    offset: usize,
+    end: usize,
+    buffer: Option<ts::Node<'a>>,
+}
+
+impl<'a, It: Iterator<Item=ts::Node<'a>>> Children<'a, It> {
+    fn new(lang: Language, text: &'a str, start: usize, end: usize, it: It) -> Children<'a, It> {
+        Children {
+            lang,
+            text,
+            it,
+            offset: start,
+            end,
+            buffer: None,
+        }
+    }
+}
+
+impl<'a, It: Iterator<Item=ts::Node<'a>>> Iterator for Children<'a, It> {
+    type Item = Child<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(node) = self.buffer.take() {
+            return Some(Child::Node(Node {
+                lang: self.lang,
+                text: self.text,
+                inner: node,
+            }));
+        }
+
+        if let Some(node) = self.it.next() {
+            let start = node.start_byte();
+            assert!(start <= self.end);
+            if start > self.offset {
+                self.buffer = Some(node);
+                let offset = self.offset;
+                self.offset = node.end_byte();
+                Some(Child::Text(&self.text[offset..start]))
+            } else {
+                self.offset = node.end_byte();
+                Some(Child::Node(Node {
+                    lang: self.lang,
+                    text: self.text,
+                    inner: node,
+                }))
+            }
+        } else if self.offset < self.end {
+            let offset = self.offset;
+            self.offset = self.end;
+            Some(Child::Text(&self.text[offset..self.end]))
+        } else {
+            None
+        }
+    }
+}
+
+struct AllWalker<'a> {
+    walker: ts::TreeCursor<'a>,
+    descend: bool,
+    done: bool,
+}
+
+impl<'a> AllWalker<'a> {
+    fn new(walker: ts::TreeCursor<'a>) -> AllWalker<'a> {
+        AllWalker {
+            walker,
+            descend: true,
+            done: false,
+        }
+    }
+}
+
+impl<'a> Iterator for AllWalker<'a> {
+    type Item = ts::Node<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let n = self.walker.node();
+        if self.descend && self.walker.goto_first_child() {
+            Some(n)
+        } else {
+            loop {
+                if self.walker.goto_next_sibling() {
+                    self.descend = true;
+                    return Some(n);
+                }
+
+                // Back at the root, so `n` is the last node.
+                if !self.walker.goto_parent() {
+                    self.done = true;
+                    return Some(n);
+                }
+            }
+        }
+    }
+}
+
+impl LanguageInfo {
+    fn new(lang: ts::Language) -> LanguageInfo {
+        let mut kinds_by_name = HashMap::new();
+        let mut kinds_by_id = Vec::new();
+
+        for k in 0..lang.node_kind_count() {
+            let name = lang.node_kind_for_id(k as u16);
+            kinds_by_id.push(name);
+            if lang.node_kind_is_named(k as u16) {
+                // TODO: deal with duplicate names (they exist!?!?!)
+                kinds_by_name.insert(name, Kind(k as u16));
+            }
+        }
+
+        LanguageInfo {
+            kinds_by_name,
+            kinds_by_id,
+        }
+    }
+
+    pub fn kind_from_name(&self, name: &str) -> Option<Kind> {
+        self.kinds_by_name.get(name).cloned()
+    }
+
+    pub fn kind_names(&self) -> &[&str] {
+        &self.kinds_by_id
+    }
+
+    pub fn kind_name(&self, kind: Kind) -> &'static str {
+        self.kinds_by_id[kind.0 as usize]
+    }
+}
+
+impl Parser {
+    pub fn new(lang_id: Language) -> Parser {
+        let lang = lang_id.ts_language();
+
+        let mut inner = ts::Parser::new();
+        inner.set_language(lang).unwrap();
+
+        Parser {
+            info: LanguageInfo::new(lang),
+            lang: lang_id,
+            inner,
+            cancellation_flag: None,
+        }
+    }
+
+    pub fn language(&self) -> Language {
+        self.lang
+    }
+
+    // The longest a single parse may take before it gives up with `ParseError::TimedOut`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        // Zero means no timeout, so a nonzero timeout of less than a microsecond rounds up.
+        let micros = timeout.map_or(0, |t| match u64::try_from(t.as_micros()).unwrap_or(u64::MAX) {
+            0 if t > Duration::from_secs(0) => 1,
+            micros => micros,
+        });
+        self.inner.set_timeout_micros(micros);
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        match self.inner.timeout_micros() {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    pub fn set_cancellation_flag(&mut self, flag: Option<CancellationFlag>) {
+        // The parser only reads through the pointer while parsing, and the `Arc` is held until
+        // it is replaced here or the parser is dropped.
+        unsafe { self.inner.set_cancellation_flag(flag.as_ref().map(|f| &*f.0)) };
+        self.cancellation_flag = flag;
+    }
+
+    pub fn cancellation_flag(&self) -> Option<&CancellationFlag> {
+        self.cancellation_flag.as_ref()
+    }
+
+    // Calls `logger` with each of the lexer's and parser's steps, for debugging grammars.
+    pub fn set_logger(&mut self, logger: Option<Logger>) {
+        self.inner.set_logger(logger);
+    }
+
+    // Writes a graph of the parse stack to `file` after each step, in Graphviz's DOT format.
+    #[cfg(unix)]
+    pub fn print_dot_graphs(&mut self, file: &impl AsRawFd) {
+        self.inner.print_dot_graphs(file);
+    }
+
+    #[cfg(unix)]
+    pub fn stop_printing_dot_graphs(&mut self) {
+        self.inner.stop_printing_dot_graphs();
+    }
+
+    fn finish(&mut self, tree: Option<ts::Tree>) -> Result<ts::Tree, ParseError> {
+        match tree {
+            Some(tree) => Ok(tree),
+            None => {
+                // Otherwise the next parse would try to resume this one.
+                self.inner.reset();
+                if matches!(&self.cancellation_flag, Some(f) if f.is_cancelled()) {
+                    Err(ParseError::Cancelled)
+                } else {
+                    Err(ParseError::TimedOut)
+                }
+            }
+        }
+    }
+
+    pub fn parse<'a>(&mut self, text: &'a str) -> Result<Tree<'a>, ParseError> {
+        let tree = self.inner.parse(text, None);
+        Ok(Tree { inner: self.finish(tree)?, lang: self.lang, text })
+    }
+
+    pub fn reparse<'a>(&mut self, text: &'a str, old: &DetachedTree) -> Result<Tree<'a>, ParseError> {
+        assert_eq!(old.lang, self.lang);
+        let tree = self.inner.parse(text, Some(&old.inner));
+        Ok(Tree { inner: self.finish(tree)?, lang: self.lang, text })
+    }
+
+    // Parses text that isn't in one piece. `input` is called with a byte offset (and its point),
+    // and returns the text from there up to wherever is convenient, or nothing at the end.
+    // There's no `&str` for the nodes to point into, so the tree comes back detached.
+    pub fn parse_with<'t, F: FnMut(usize, Point) -> &'t [u8]>(&mut self, mut input: F, old: Option<&DetachedTree>) -> Result<DetachedTree, ParseError> {
+        if let Some(old) = old {
+            assert_eq!(old.lang, self.lang);
+        }
+        let tree = self.inner.parse_with(&mut input, old.map(|t| &t.inner));
+        Ok(DetachedTree { inner: self.finish(tree)?, lang: self.lang })
+    }
+
+    pub fn parse_chunks<T: TextChunks + ?Sized>(&mut self, text: &T, old: Option<&DetachedTree>) -> Result<DetachedTree, ParseError> {
+        self.parse_with(|offset, _| text.chunk_at(offset), old)
+    }
+
+    pub fn parse_owned(&mut self, text: impl Into<Arc<str>>) -> Result<OwnedTree, ParseError> {
+        let text = text.into();
+        let inner = self.parse(&text)?.inner;
+        Ok(OwnedTree { inner, lang: self.lang, text })
+    }
+
+    pub fn reparse_owned(&mut self, text: impl Into<Arc<str>>, old: &DetachedTree) -> Result<OwnedTree, ParseError> {
+        let text = text.into();
+        let inner = self.reparse(&text, old)?.inner;
+        Ok(OwnedTree { inner, lang: self.lang, text })
+    }
+
+    pub fn parse_utf16<'a>(&mut self, text: &'a [u16]) -> Result<Utf16Tree<'a>, ParseError> {
+        let tree = self.inner.parse_utf16(text, None);
+        Ok(Utf16Tree { inner: self.finish(tree)?, lang: self.lang, text })
+    }
+
+    pub fn reparse_utf16<'a>(&mut self, text: &'a [u16], old: &DetachedTree) -> Result<Utf16Tree<'a>, ParseError> {
+        assert_eq!(old.lang, self.lang);
+        let tree = self.inner.parse_utf16(text, Some(&old.inner));
+        Ok(Utf16Tree { inner: self.finish(tree)?, lang: self.lang, text })
+    }
+
+    // Parse only the given byte ranges of `text`, keeping node offsets relative to the whole text.
+    pub(crate) fn parse_ranges<'a>(&mut self, text: &'a str, ranges: &[ts::Range]) -> Result<Tree<'a>, ParseError> {
+        self.inner.set_included_ranges(ranges);
+        let tree = self.parse(text);
+        self.inner.set_included_ranges(&[whole_document()]);
+        tree
+    }
+}
+
+fn whole_document() -> ts::Range {
+    ts::Range {
+        start_byte: 0,
+        end_byte: u32::MAX as usize,
+        start_point: ts::Point::new(0, 0),
+        end_point: ts::Point::new(u32::MAX as usize, u32::MAX as usize),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel() {
+        let text = "x = [1, 2, 3]\n".repeat(10000);
+        let mut parser = Parser::new(Language::Python);
+
+        let flag = CancellationFlag::new();
+        parser.set_cancellation_flag(Some(flag.clone()));
+        flag.cancel();
+        assert_eq!(parser.parse(&text).err(), Some(ParseError::Cancelled));
+
+        // The parser starts afresh rather than resuming the cancelled parse.
+        flag.reset();
+        assert!(!parser.parse("y = 2\n").unwrap().root().has_error());
+
+        parser.set_cancellation_flag(None);
+        parser.set_timeout(Some(Duration::from_micros(1)));
+        assert_eq!(parser.timeout(), Some(Duration::from_micros(1)));
+        assert_eq!(parser.parse(&text).err(), Some(ParseError::TimedOut));
+
+        parser.set_timeout(Some(Duration::from_nanos(500)));
+        assert_eq!(parser.parse(&text).err(), Some(ParseError::TimedOut));
+
+        // Too long to count in microseconds, so as good as none.
+        parser.set_timeout(Some(Duration::from_secs(u64::MAX)));
+        assert!(parser.parse(&text).is_ok());
+
+        parser.set_timeout(None);
+        assert_eq!(parser.parse(&text).unwrap().root().nodes().count(), 10000);
+    }
+
+    #[test]
+    fn test_tree_nodes() {
+        let tree = Parser::new(Language::Python).parse("x = f(a, b)").unwrap();
+        let nodes = tree.nodes().collect::<Vec<_>>();
+
+        // Every node once, in order, down to the closing paren that ends the file.
+        assert_eq!(nodes.first().unwrap().kind_name(), "module");
+        let last = nodes.last().unwrap();
+        assert_eq!((last.kind_name(), last.end_byte()), (")", 11));
+        assert_eq!(nodes.iter().filter(|n| n.nodes().next().is_none()).map(|n| n.text()).collect::<String>(), "x=f(a,b)");
+    }
+
+    #[test]
+    fn test_owned_tree() {
+        let mut parser = Parser::new(Language::Python);
+        let tree = parser.parse_owned(String::from("x = 1\n")).unwrap();
+
+        // The tree and its text can move to another thread together.
+        let tree = std::thread::spawn(move || {
+            assert_eq!(tree.root().nodes().next().unwrap().text(), "x = 1");
+            tree
+        }).join().unwrap();
+
+        let mut old = tree.detach();
+        old.edit(&InputEdit {
+            start_byte: 4,
+            old_end_byte: 5,
+            new_end_byte: 7,
+            start_position: Point::new(0, 4),
+            old_end_position: Point::new(0, 5),
+            new_end_position: Point::new(0, 7),
+        });
+        let new = parser.reparse_owned("x = 100\n", &old).unwrap();
+        assert_eq!(new.nodes().last().unwrap().text(), "100");
+        assert_eq!(new.tree().root().text(), "x = 100\n");
+        assert_eq!(tree.text(), "x = 1\n");
+
+        let owned = parser.parse("y = 2\n").unwrap().into_owned();
+        assert_eq!(&*owned.shared_text(), "y = 2\n");
+    }
+
+    #[test]
+    fn test_utf16() {
+        let text = "s = \"😀é\"\nt = 2\n".encode_utf16().collect::<Vec<_>>();
+        let mut parser = Parser::new(Language::Python);
+        let tree = parser.parse_utf16(&text).unwrap();
+
+        let string = tree.nodes().find(|n| n.kind_name() == "string").unwrap();
+        assert_eq!(String::from_utf16(string.text()).unwrap(), "\"😀é\"");
+        assert_eq!((string.start(), string.end()), (4, 9));
+        assert_eq!(string.end_point(), Point::new(0, 9));
+
+        let t = tree.nodes().filter(|n| n.kind_name() == "identifier").nth(1).unwrap();
+        assert_eq!(t.start_point(), Point::new(1, 0));
+        assert_eq!(t.text(), &text[10..11]);
+        assert_eq!(tree.root().nodes().count(), 2);
+    }
+
+    #[test]
+    fn test_logger() {
+        let events = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let log = events.clone();
+
+        let mut parser = Parser::new(Language::Python);
+        parser.set_logger(Some(Box::new(move |log_type, message| log.borrow_mut().push((log_type, message.to_string())))));
+        parser.parse("x\n").unwrap();
+        parser.set_logger(None);
+
+        let events = events.borrow();
+        assert_eq!(events[0], (LogType::Parse, "new_parse".to_string()));
+        assert!(events.iter().any(|(t, m)| *t == LogType::Lex && m.starts_with("consume character:'x'")));
+        assert_eq!(events.last().unwrap(), &(LogType::Parse, "done".to_string()));
+    }
+
+    struct Lines(Vec<&'static str>);
+
+    impl TextChunks for Lines {
+        fn chunk_at(&self, mut offset: usize) -> &[u8] {
+            for line in &self.0 {
+                if offset < line.len() {
+                    return &line.as_bytes()[offset..];
+                }
+                offset -= line.len();
+            }
+            &[]
+        }
+    }
+
+    #[test]
+    fn test_parse_chunks() {
+        let lines = Lines(vec!["def f(x):\n", "    return \"é", "\"\n", "f(1)\n"]);
+        let text = lines.0.concat();
+
+        let mut parser = Parser::new(Language::Python);
+        let tree = parser.parse_chunks(&lines, None).unwrap();
+        assert_eq!(format!("{:?}", tree.attach(&text)), format!("{:?}", parser.parse(&text).unwrap()));
+
+        let tree = parser.parse_chunks(text.as_str(), Some(&tree)).unwrap();
+        assert_eq!(tree.attach(&text).root().nodes().count(), 2);
+
+        #[cfg(feature = "ropey")]
+        {
+            let rope = ropey::Rope::from_str(&text.repeat(100));
+            let tree = parser.parse_chunks(&rope, None).unwrap();
+            assert_eq!(tree.attach(&text.repeat(100)).root().nodes().count(), 200);
+        }
+    }
+}
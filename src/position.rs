@@ -0,0 +1,186 @@
+use crate::parse::Point;
+use failure::{Error, format_err};
+use std::str::FromStr;
+
+extern "C" {
+    // From the utf8proc that's compiled into the tree-sitter runtime.
+    fn utf8proc_grapheme_break_stateful(c1: i32, c2: i32, state: *mut i32) -> bool;
+}
+
+// What a column counts. Points count bytes; LSP counts UTF-16 code units by default, and
+// editors usually show characters or graphemes.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ColumnUnit {
+    Byte,
+    Utf16,
+    Char,
+    Grapheme,
+}
+
+impl FromStr for ColumnUnit {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Error> {
+        Ok(match text {
+            "byte" | "utf-8" => ColumnUnit::Byte,
+            "utf-16" => ColumnUnit::Utf16,
+            "char" | "utf-32" => ColumnUnit::Char,
+            "grapheme" => ColumnUnit::Grapheme,
+            _ => return Err(format_err!("invalid column unit '{}'", text))
+        })
+    }
+}
+
+// The byte offsets where each extended grapheme cluster of `text` starts, followed by its length.
+pub fn grapheme_boundaries(text: &str) -> Vec<usize> {
+    let mut res = Vec::new();
+    let mut state = 0;
+    let mut prev = None;
+    for (i, c) in text.char_indices() {
+        let brk = match prev {
+            None => true,
+            Some(p) => unsafe { utf8proc_grapheme_break_stateful(p as i32, c as i32, &mut state) },
+        };
+        if brk {
+            res.push(i);
+        }
+        prev = Some(c);
+    }
+    res.push(text.len());
+    res
+}
+
+// The length of `text` in `unit`s.
+pub fn column_width(text: &str, unit: ColumnUnit) -> usize {
+    match unit {
+        ColumnUnit::Byte => text.len(),
+        ColumnUnit::Utf16 => text.encode_utf16().count(),
+        ColumnUnit::Char => text.chars().count(),
+        ColumnUnit::Grapheme => grapheme_boundaries(text).len() - 1,
+    }
+}
+
+// The byte offset `count` units into `text`. An offset inside a character (or grapheme) moves to
+// the end of it, and one past the end of `text` stops there.
+pub fn column_offset(text: &str, count: usize, unit: ColumnUnit) -> usize {
+    let mut seen = 0;
+    match unit {
+        ColumnUnit::Byte => {
+            let mut i = count.min(text.len());
+            while !text.is_char_boundary(i) {
+                i += 1;
+            }
+            return i;
+        }
+        ColumnUnit::Utf16 | ColumnUnit::Char => {
+            for (i, c) in text.char_indices() {
+                if seen >= count {
+                    return i;
+                }
+                seen += if unit == ColumnUnit::Utf16 { c.len_utf16() } else { 1 };
+            }
+        }
+        ColumnUnit::Grapheme => {
+            return grapheme_boundaries(text).get(count).cloned().unwrap_or(text.len());
+        }
+    }
+    text.len()
+}
+
+// The start of each line of a text, for converting between byte offsets, points, and columns
+// in other units.
+pub struct LineIndex<'a> {
+    text: &'a str,
+    starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(text: &'a str) -> LineIndex<'a> {
+        let mut starts = vec![0];
+        starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        LineIndex { text, starts }
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.starts.len()
+    }
+
+    // The text of line `row`, without its newline. Rows past the end give the last line.
+    pub fn line(&self, row: usize) -> &'a str {
+        let row = row.min(self.starts.len() - 1);
+        let end = self.starts.get(row + 1).map_or(self.text.len(), |e| e - 1);
+        &self.text[self.starts[row]..end]
+    }
+
+    pub fn point(&self, offset: usize) -> Point {
+        let offset = offset.min(self.text.len());
+        let row = self.starts.binary_search(&offset).unwrap_or_else(|i| i - 1);
+        Point::new(row, offset - self.starts[row])
+    }
+
+    pub fn offset(&self, point: Point) -> usize {
+        if point.row >= self.starts.len() {
+            return self.text.len();
+        }
+        self.starts[point.row] + point.column.min(self.line(point.row).len())
+    }
+
+    // The column of `point` counted in `unit`s.
+    pub fn column(&self, point: Point, unit: ColumnUnit) -> usize {
+        let line = self.line(point.row);
+        let mut column = point.column.min(line.len());
+        while !line.is_char_boundary(column) {
+            column -= 1;
+        }
+        column_width(&line[..column], unit)
+    }
+
+    // The point `column` units into line `row`. Rows past the end give the end of the text.
+    pub fn point_at(&self, row: usize, column: usize, unit: ColumnUnit) -> Point {
+        if row >= self.starts.len() {
+            return self.point(self.text.len());
+        }
+        Point::new(row, column_offset(self.line(row), column, unit))
+    }
+
+    pub fn utf16_offset(&self, offset: usize) -> usize {
+        column_width(&self.text[..offset.min(self.text.len())], ColumnUnit::Utf16)
+    }
+
+    pub fn offset_from_utf16(&self, units: usize) -> usize {
+        column_offset(self.text, units, ColumnUnit::Utf16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_index() {
+        let text = "a = 1\nb = \"😀e\u{301}🇳🇿\"\n";
+        let lines = LineIndex::new(text);
+        assert_eq!(lines.line_count(), 3);
+        assert_eq!(lines.line(1), "b = \"😀e\u{301}🇳🇿\"");
+
+        // After the closing quote.
+        let end = lines.point(text.len() - 1);
+        assert_eq!(end, Point::new(1, 21));
+        assert_eq!(lines.offset(end), text.len() - 1);
+        assert_eq!(lines.column(end, ColumnUnit::Byte), 21);
+        assert_eq!(lines.column(end, ColumnUnit::Utf16), 14);
+        assert_eq!(lines.column(end, ColumnUnit::Char), 11);
+        assert_eq!(lines.column(end, ColumnUnit::Grapheme), 9);
+
+        for &unit in &[ColumnUnit::Byte, ColumnUnit::Utf16, ColumnUnit::Char, ColumnUnit::Grapheme] {
+            assert_eq!(lines.point_at(1, lines.column(end, unit), unit), end);
+        }
+
+        // Halfway through the emoji's surrogate pair.
+        assert_eq!(lines.point_at(1, 6, ColumnUnit::Utf16), Point::new(1, 9));
+        assert_eq!(lines.point_at(5, 0, ColumnUnit::Char), Point::new(2, 0));
+        assert_eq!(lines.utf16_offset(text.len()), 21);
+        assert_eq!(lines.offset_from_utf16(21), text.len());
+        assert_eq!("grapheme".parse::<ColumnUnit>().unwrap(), ColumnUnit::Grapheme);
+    }
+}
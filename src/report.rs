@@ -0,0 +1,272 @@
+use crate::lint::{Severity, Diagnostic, LintedFile};
+use failure::{Error, format_err};
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReportFormat {
+    Text,
+    Sarif,
+    Checkstyle,
+    Junit,
+}
+
+impl FromStr for ReportFormat {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Error> {
+        Ok(match text {
+            "text" => ReportFormat::Text,
+            "sarif" => ReportFormat::Sarif,
+            "checkstyle" => ReportFormat::Checkstyle,
+            "junit" => ReportFormat::Junit,
+            _ => return Err(format_err!("invalid report format '{}'", text))
+        })
+    }
+}
+
+pub fn write_report<W: Write>(out: &mut W, format: ReportFormat, files: &[LintedFile]) -> io::Result<()> {
+    match format {
+        ReportFormat::Text => write_text(out, files),
+        ReportFormat::Sarif => write_sarif(out, files),
+        ReportFormat::Checkstyle => write_checkstyle(out, files),
+        ReportFormat::Junit => write_junit(out, files),
+    }
+}
+
+// One `path:line:column: severity[rule] message` line per diagnostic, like a compiler would.
+pub fn write_text<W: Write>(out: &mut W, files: &[LintedFile]) -> io::Result<()> {
+    for file in files {
+        for d in &file.diagnostics {
+            let p = d.range.start_point;
+            writeln!(out, "{}:{}:{}: {}[{}] {}", file.path, p.row + 1, p.column + 1, d.severity.name(), d.rule, d.message)?;
+        }
+    }
+    Ok(())
+}
+
+// A relative path stays relative (SARIF resolves it against the run's `SRCROOT`), and an absolute
+// one becomes a `file://` URI.
+fn file_uri(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let mut res = String::new();
+    if path.starts_with('/') {
+        res.push_str("file://");
+    } else if path.as_bytes().get(1) == Some(&b':') {
+        res.push_str("file:///");
+    }
+
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => res.push(b as char),
+            _ => res.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    res
+}
+
+// SARIF counts columns and offsets in UTF-16 code units unless a run says otherwise.
+fn sarif_region(d: &Diagnostic) -> Value {
+    let range = d.utf16_range;
+    let (start, end) = (range.start_point, range.end_point);
+    json!({
+        "startLine": start.row + 1,
+        "startColumn": start.column + 1,
+        "endLine": end.row + 1,
+        "endColumn": end.column + 1,
+        "charOffset": range.start_byte,
+        "charLength": range.end_byte - range.start_byte,
+    })
+}
+
+// SARIF 2.1.0, with one run for all the files. The rules are the ones that have results.
+pub fn sarif(files: &[LintedFile]) -> Value {
+    let mut rules: Vec<(&str, Severity)> = Vec::new();
+    let mut results = Vec::new();
+
+    for file in files {
+        let uri = file_uri(&file.path);
+        let location = if uri.starts_with("file:") {
+            json!({ "uri": uri })
+        } else {
+            json!({ "uri": uri, "uriBaseId": "SRCROOT" })
+        };
+
+        for d in &file.diagnostics {
+            let rule_index = match rules.iter().position(|(id, _)| *id == d.rule) {
+                Some(i) => i,
+                None => {
+                    rules.push((&d.rule, d.severity));
+                    rules.len() - 1
+                }
+            };
+
+            let mut result = json!({
+                "ruleId": d.rule,
+                "ruleIndex": rule_index,
+                "level": d.severity.name(),
+                "message": { "text": d.message },
+                "locations": [{
+                    "physicalLocation": { "artifactLocation": location.clone(), "region": sarif_region(d) },
+                }],
+            });
+            if let Some(fix) = &d.fix {
+                result["fixes"] = json!([{
+                    "description": { "text": format!("Replace with `{}`", fix) },
+                    "artifactChanges": [{
+                        "artifactLocation": location.clone(),
+                        "replacements": [{
+                            "deletedRegion": sarif_region(d),
+                            "insertedContent": { "text": fix },
+                        }],
+                    }],
+                }]);
+            }
+            results.push(result);
+        }
+    }
+
+    let rules = rules.iter()
+        .map(|(id, severity)| json!({ "id": id, "defaultConfiguration": { "level": severity.name() } }))
+        .collect::<Vec<_>>();
+
+    json!({
+        "version": "2.1.0",
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "runs": [{
+            "tool": { "driver": { "name": "hornbeam", "rules": rules } },
+            "results": results,
+        }],
+    })
+}
+
+pub fn write_sarif<W: Write>(out: &mut W, files: &[LintedFile]) -> io::Result<()> {
+    serde_json::to_writer_pretty(&mut *out, &sarif(files))?;
+    writeln!(out)
+}
+
+fn xml_escape(text: &str) -> String {
+    let mut res = String::new();
+    for c in text.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&apos;"),
+            '\n' => res.push_str("&#10;"),
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+// Checkstyle's format, which most CI systems can show inline. Checkstyle calls a note `info`.
+pub fn write_checkstyle<W: Write>(out: &mut W, files: &[LintedFile]) -> io::Result<()> {
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(out, "<checkstyle version=\"4.3\">")?;
+    for file in files {
+        writeln!(out, "  <file name=\"{}\">", xml_escape(&file.path))?;
+        for d in &file.diagnostics {
+            let severity = match d.severity {
+                Severity::Note => "info",
+                s => s.name(),
+            };
+            let p = d.range.start_point;
+            writeln!(out, "    <error line=\"{}\" column=\"{}\" severity=\"{}\" message=\"{}\" source=\"hornbeam.{}\"/>",
+                p.row + 1, p.column + 1, severity, xml_escape(&d.message), xml_escape(&d.rule))?;
+        }
+        writeln!(out, "  </file>")?;
+    }
+    writeln!(out, "</checkstyle>")
+}
+
+// JUnit's format, with a test suite per file and a failing test case per diagnostic. A file
+// without diagnostics gets a single passing test case, so that it still shows up.
+pub fn write_junit<W: Write>(out: &mut W, files: &[LintedFile]) -> io::Result<()> {
+    let tests = files.iter().map(|f| f.diagnostics.len().max(1)).sum::<usize>();
+    let failures = files.iter().map(|f| f.diagnostics.len()).sum::<usize>();
+
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(out, "<testsuites name=\"hornbeam\" tests=\"{}\" failures=\"{}\">", tests, failures)?;
+    for file in files {
+        let path = xml_escape(&file.path);
+        writeln!(out, "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">", path, file.diagnostics.len().max(1), file.diagnostics.len())?;
+        if file.diagnostics.is_empty() {
+            writeln!(out, "    <testcase name=\"hornbeam\" classname=\"{}\"/>", path)?;
+        }
+        for d in &file.diagnostics {
+            let p = d.range.start_point;
+            let location = format!("{}:{}:{}", path, p.row + 1, p.column + 1);
+            let mut body = format!("{}: {}", location, xml_escape(&d.message));
+            if let Some(fix) = &d.fix {
+                body.push_str(&format!("&#10;fix: {}", xml_escape(fix)));
+            }
+            writeln!(out, "    <testcase name=\"{}\" classname=\"{}\">", xml_escape(&d.rule), location)?;
+            writeln!(out, "      <failure message=\"{}\" type=\"{}\">{}</failure>", xml_escape(&d.message), d.severity.name(), body)?;
+            writeln!(out, "    </testcase>")?;
+        }
+        writeln!(out, "  </testsuite>")?;
+    }
+    writeln!(out, "</testsuites>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{Parser, Language};
+
+    #[test]
+    fn test_reports() {
+        let text = "x = 1\nprint(\"<a>\")\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let call = tree.nodes().find(|n| n.kind_name() == "call").unwrap();
+
+        let mut diagnostic = Diagnostic::new("no-print", Severity::Note, "print of \"<a>\"".to_string(), &call);
+        diagnostic.fix = Some("log(\"<a>\")".to_string());
+        let files = [
+            LintedFile { path: "src/a b.py".to_string(), diagnostics: vec![diagnostic] },
+            LintedFile { path: "/src/c.py".to_string(), diagnostics: vec![] },
+        ];
+
+        let sarif = sarif(&files);
+        let result = &sarif["runs"][0]["results"][0];
+        assert_eq!(result["locations"][0]["physicalLocation"]["artifactLocation"]["uri"], "src/a%20b.py");
+        assert_eq!(result["locations"][0]["physicalLocation"]["region"], json!({
+            "startLine": 2, "startColumn": 1, "endLine": 2, "endColumn": 13, "charOffset": 6, "charLength": 12,
+        }));
+        assert_eq!(result["fixes"][0]["artifactChanges"][0]["replacements"][0]["insertedContent"]["text"], "log(\"<a>\")");
+        assert_eq!(sarif["runs"][0]["tool"]["driver"]["rules"][0]["id"], "no-print");
+        assert_eq!(file_uri("/src/c.py"), "file:///src/c.py");
+
+        // Two bytes for `é` and four for the emoji, but one and two UTF-16 code units.
+        let text = "s = 'é😀'; print(\"<a>\")\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let call = tree.nodes().find(|n| n.kind_name() == "call").unwrap();
+        let diagnostic = Diagnostic::new("no-print", Severity::Note, "print".to_string(), &call);
+        assert_eq!(diagnostic.range.start_point.column, 14);
+        assert_eq!(sarif_region(&diagnostic), json!({
+            "startLine": 1, "startColumn": 12, "endLine": 1, "endColumn": 24, "charOffset": 11, "charLength": 12,
+        }));
+
+        let mut out = Vec::new();
+        write_checkstyle(&mut out, &files).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<checkstyle version=\"4.3\">\n",
+            "  <file name=\"src/a b.py\">\n",
+            "    <error line=\"2\" column=\"1\" severity=\"info\" message=\"print of &quot;&lt;a&gt;&quot;\" source=\"hornbeam.no-print\"/>\n",
+            "  </file>\n",
+            "  <file name=\"/src/c.py\">\n",
+            "  </file>\n",
+            "</checkstyle>\n",
+        ));
+
+        let mut out = Vec::new();
+        write_junit(&mut out, &files).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("<testsuites name=\"hornbeam\" tests=\"2\" failures=\"1\">"));
+        assert!(out.contains("<testcase name=\"hornbeam\" classname=\"/src/c.py\"/>"));
+    }
+}
@@ -0,0 +1,308 @@
+use crate::parse::{Language, Node};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SymbolKind {
+    Function,
+    Method,
+    Class,
+    Struct,
+    Enum,
+    Interface,
+    Module,
+    Impl,
+    Type,
+    Constant,
+    Macro,
+}
+
+impl SymbolKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            SymbolKind::Function => "function",
+            SymbolKind::Method => "method",
+            SymbolKind::Class => "class",
+            SymbolKind::Struct => "struct",
+            SymbolKind::Enum => "enum",
+            SymbolKind::Interface => "interface",
+            SymbolKind::Module => "module",
+            SymbolKind::Impl => "implementation",
+            SymbolKind::Type => "type",
+            SymbolKind::Constant => "constant",
+            SymbolKind::Macro => "macro",
+        }
+    }
+
+    fn is_container(self) -> bool {
+        matches!(self, SymbolKind::Class | SymbolKind::Struct | SymbolKind::Interface | SymbolKind::Impl)
+    }
+}
+
+// Node kinds that define a named symbol. None of the bundled grammars use fields yet, so the
+// name is found by looking for an identifier-like child, or in C by following the declarator
+// (see `symbol_name`).
+pub(crate) fn definitions(lang: Language) -> &'static [(&'static str, SymbolKind)] {
+    match lang {
+        Language::Rust => &[
+            ("function_item", SymbolKind::Function),
+            ("function_signature_item", SymbolKind::Function),
+            ("struct_item", SymbolKind::Struct),
+            ("union_item", SymbolKind::Struct),
+            ("enum_item", SymbolKind::Enum),
+            ("trait_item", SymbolKind::Interface),
+            ("impl_item", SymbolKind::Impl),
+            ("mod_item", SymbolKind::Module),
+            ("type_item", SymbolKind::Type),
+            ("const_item", SymbolKind::Constant),
+            ("static_item", SymbolKind::Constant),
+            ("macro_definition", SymbolKind::Macro),
+        ],
+        Language::Python => &[
+            ("function_definition", SymbolKind::Function),
+            ("class_definition", SymbolKind::Class),
+        ],
+        Language::Javascript => &[
+            ("function", SymbolKind::Function),
+            ("generator_function", SymbolKind::Function),
+            ("class", SymbolKind::Class),
+            ("method_definition", SymbolKind::Method),
+        ],
+        Language::Typescript => &[
+            ("function", SymbolKind::Function),
+            ("generator_function", SymbolKind::Function),
+            ("function_signature", SymbolKind::Function),
+            ("class", SymbolKind::Class),
+            ("abstract_class", SymbolKind::Class),
+            ("method_definition", SymbolKind::Method),
+            ("method_signature", SymbolKind::Method),
+            ("interface_declaration", SymbolKind::Interface),
+            ("enum_declaration", SymbolKind::Enum),
+            ("type_alias_declaration", SymbolKind::Type),
+            ("module", SymbolKind::Module),
+            ("internal_module", SymbolKind::Module),
+        ],
+        Language::C => &[
+            ("function_definition", SymbolKind::Function),
+            ("struct_specifier", SymbolKind::Struct),
+            ("union_specifier", SymbolKind::Struct),
+            ("enum_specifier", SymbolKind::Enum),
+            ("type_definition", SymbolKind::Type),
+            ("preproc_def", SymbolKind::Macro),
+            ("preproc_function_def", SymbolKind::Macro),
+        ],
+        Language::Cpp => &[
+            ("function_definition", SymbolKind::Function),
+            ("class_specifier", SymbolKind::Class),
+            ("struct_specifier", SymbolKind::Struct),
+            ("union_specifier", SymbolKind::Struct),
+            ("enum_specifier", SymbolKind::Enum),
+            ("namespace_definition", SymbolKind::Module),
+            ("type_definition", SymbolKind::Type),
+            ("alias_declaration", SymbolKind::Type),
+            ("preproc_def", SymbolKind::Macro),
+            ("preproc_function_def", SymbolKind::Macro),
+        ],
+        Language::Go => &[
+            ("function_declaration", SymbolKind::Function),
+            ("method_declaration", SymbolKind::Method),
+            ("type_spec", SymbolKind::Type),
+            ("const_spec", SymbolKind::Constant),
+        ],
+        Language::Ruby => &[
+            ("method", SymbolKind::Function),
+            ("singleton_method", SymbolKind::Function),
+            ("class", SymbolKind::Class),
+            ("module", SymbolKind::Module),
+        ],
+        Language::Php => &[
+            ("function_definition", SymbolKind::Function),
+            ("method_declaration", SymbolKind::Method),
+            ("class_declaration", SymbolKind::Class),
+            ("interface_declaration", SymbolKind::Interface),
+            ("trait_declaration", SymbolKind::Interface),
+            ("namespace_definition", SymbolKind::Module),
+        ],
+        Language::CSharp => &[
+            ("method_declaration", SymbolKind::Method),
+            ("constructor_declaration", SymbolKind::Method),
+            ("class_declaration", SymbolKind::Class),
+            ("struct_declaration", SymbolKind::Struct),
+            ("interface_declaration", SymbolKind::Interface),
+            ("enum_declaration", SymbolKind::Enum),
+            ("namespace_declaration", SymbolKind::Module),
+        ],
+        Language::Java => &[
+            ("method_declaration", SymbolKind::Method),
+            ("constructor_declaration", SymbolKind::Method),
+            ("class_declaration", SymbolKind::Class),
+            ("interface_declaration", SymbolKind::Interface),
+            ("enum_declaration", SymbolKind::Enum),
+        ],
+        Language::Scala => &[
+            ("function_definition", SymbolKind::Function),
+            ("function_declaration", SymbolKind::Function),
+            ("class_definition", SymbolKind::Class),
+            ("object_definition", SymbolKind::Module),
+            ("trait_definition", SymbolKind::Interface),
+            ("type_definition", SymbolKind::Type),
+        ],
+        Language::Julia => &[
+            ("function_definition", SymbolKind::Function),
+            ("struct_definition", SymbolKind::Struct),
+            ("module_definition", SymbolKind::Module),
+        ],
+        Language::Bash => &[
+            ("function_definition", SymbolKind::Function),
+        ],
+        Language::Ocaml => &[
+            ("let_binding", SymbolKind::Function),
+            ("module_binding", SymbolKind::Module),
+            ("type_binding", SymbolKind::Type),
+            ("class_binding", SymbolKind::Class),
+        ],
+        _ => &[],
+    }
+}
+
+// Node kinds that refer to a symbol by calling it.
+pub(crate) fn references(lang: Language) -> &'static [&'static str] {
+    match lang {
+        Language::Rust => &["call_expression", "macro_invocation"],
+        Language::Python => &["call"],
+        Language::Javascript |
+        Language::Typescript => &["call_expression", "new_expression"],
+        Language::C |
+        Language::Cpp |
+        Language::Go |
+        Language::Scala |
+        Language::Julia => &["call_expression"],
+        Language::Ruby => &["method_call"],
+        Language::Php => &["function_call_expression", "member_call_expression"],
+        Language::Bash => &["command"],
+        _ => &[],
+    }
+}
+
+const NAME_KINDS: &[&str] = &[
+    "identifier",
+    "type_identifier",
+    "field_identifier",
+    "property_identifier",
+    "identifier_name",
+    "constant",
+    "name",
+    "word",
+    "command_name",
+    "value_name",
+    "destructor_name",
+    "operator_name",
+];
+
+pub(crate) fn is_name(node: &Node) -> bool {
+    NAME_KINDS.contains(&node.kind_name())
+}
+
+pub(crate) fn definition_kind(node: &Node) -> Option<SymbolKind> {
+    let kind = node.kind_name();
+    let symbol = definitions(node.language()).iter().find(|(k, _)| *k == kind).map(|(_, s)| *s)?;
+
+    // Without a body, `struct foo` only refers to the struct, e.g. in a return type.
+    if kind.ends_with("_specifier") && !node.nodes().any(|n| n.kind_name() == "field_declaration_list" || n.kind_name() == "enumerator_list") {
+        return None;
+    }
+
+    Some(symbol)
+}
+
+// The kind of symbol `node` defines, if any; functions directly inside a class-like symbol
+// are reported as methods.
+pub fn symbol_kind(node: &Node) -> Option<SymbolKind> {
+    let kind = definition_kind(node)?;
+    if kind != SymbolKind::Function {
+        return Some(kind);
+    }
+
+    let mut parent = node.parent();
+    while let Some(p) = parent {
+        if let Some(outer) = definition_kind(&p) {
+            return Some(if outer.is_container() { SymbolKind::Method } else { kind });
+        }
+        parent = p.parent();
+    }
+
+    Some(kind)
+}
+
+// The node holding the name of the symbol that `node` defines.
+pub fn symbol_name<'a>(node: &Node<'a>) -> Option<Node<'a>> {
+    // In `impl<T> Trait for Type<T>`, the name is the type's, after the `for` (which some
+    // versions of the grammar wrap in an `impl_for_clause`).
+    if node.kind_name() == "impl_item" {
+        let node = node.nodes().find(|n| n.kind_name() == "impl_for_clause").unwrap_or_else(|| node.clone());
+        let children = node.nodes().collect::<Vec<_>>();
+        let start = children.iter().position(|n| n.kind_name() == "for").map_or(0, |i| i + 1);
+        return children[start..].iter().filter_map(type_name).next();
+    }
+
+    let is_c = node.language() == Language::C || node.language() == Language::Cpp;
+    match node.kind_name() {
+        // The type comes first, so the name is in the last declarator, as in `foo_t *make_foo(void)`
+        // and `typedef foo_t *bar_t;`.
+        "function_definition" | "type_definition" if is_c => {
+            return node.nodes().filter(|n| is_name(n) || is_declarator(n)).last().and_then(|n| declarator_name(&n));
+        }
+        // The return type comes first, so the name is the last one before the parameters.
+        "method_declaration" | "constructor_declaration" if node.language() == Language::Java || node.language() == Language::CSharp => {
+            return node.nodes()
+                .take_while(|n| n.kind_name() != "formal_parameters" && n.kind_name() != "parameter_list")
+                .filter(is_name)
+                .last();
+        }
+        _ => {}
+    }
+
+    if let Some(name) = node.nodes().find(is_name) {
+        return Some(name);
+    }
+
+    // C-like declarators nest the name: `int *(*foo)(void)`.
+    node.nodes()
+        .filter(|n| n.kind_name().ends_with("declarator") || n.kind_name() == "generic_type")
+        .filter_map(|n| symbol_name(&n))
+        .next()
+}
+
+fn is_declarator(node: &Node) -> bool {
+    node.kind_name().ends_with("declarator")
+}
+
+// The name a C declarator declares, e.g. `foo` in `*(*foo)(void)`, without its scope in C++.
+fn declarator_name<'a>(node: &Node<'a>) -> Option<Node<'a>> {
+    match node.kind_name() {
+        _ if is_name(node) => Some(node.clone()),
+        "scoped_identifier" | "qualified_identifier" => node.nodes().filter(is_name).last(),
+        // The declarator comes before any parameters or array size.
+        _ => node.nodes()
+            .find(|n| is_name(n) || is_declarator(n) || n.kind_name() == "scoped_identifier" || n.kind_name() == "qualified_identifier")
+            .and_then(|n| declarator_name(&n)),
+    }
+}
+
+// The name in a type, without its path or type arguments.
+fn type_name<'a>(node: &Node<'a>) -> Option<Node<'a>> {
+    match node.kind_name() {
+        "generic_type" => type_name(&node.nodes().next()?),
+        "scoped_type_identifier" => node.nodes().filter(is_name).last(),
+        _ if is_name(node) => Some(node.clone()),
+        _ => None,
+    }
+}
+
+// The node holding the name of the function called by `node`.
+pub fn callee_name<'a>(node: &Node<'a>) -> Option<Node<'a>> {
+    let callee = node.nodes().next()?;
+    if is_name(&callee) {
+        Some(callee)
+    } else {
+        callee.nodes().filter(is_name).last()
+    }
+}
@@ -0,0 +1,219 @@
+use crate::parse::Tree;
+use crate::symbols::{self, SymbolKind};
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Tag {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub is_definition: bool,
+    // Zero-based row of the name.
+    pub row: usize,
+    // Byte offset of the start of the line containing the name.
+    pub line_start: usize,
+    // Text of the line containing the name, up to and including the name.
+    pub prefix: String,
+    pub line: String,
+}
+
+pub struct TaggedFile {
+    pub path: String,
+    pub tags: Vec<Tag>,
+}
+
+impl Tag {
+    pub fn extract(tree: &Tree) -> Vec<Tag> {
+        let text = tree.text();
+        let refs = symbols::references(tree.language());
+        let mut tags = Vec::new();
+
+        for node in tree.nodes() {
+            let (kind, is_definition, name) = if let Some(kind) = symbols::symbol_kind(&node) {
+                (kind, true, symbols::symbol_name(&node))
+            } else if refs.contains(&node.kind_name()) {
+                (SymbolKind::Function, false, symbols::callee_name(&node))
+            } else {
+                continue;
+            };
+
+            let name = match name {
+                Some(name) => name,
+                None => continue,
+            };
+
+            let line_start = text[..name.start_byte()].rfind('\n').map_or(0, |i| i + 1);
+            let line_end = text[name.start_byte()..].find('\n').map_or(text.len(), |i| name.start_byte() + i);
+
+            tags.push(Tag {
+                name: name.text().to_string(),
+                kind,
+                is_definition,
+                row: name.start_point().row,
+                line_start,
+                prefix: text[line_start..name.end_byte()].to_string(),
+                line: text[line_start..line_end].to_string(),
+            });
+        }
+
+        tags
+    }
+}
+
+fn ctags_letter(kind: SymbolKind) -> char {
+    match kind {
+        SymbolKind::Function => 'f',
+        SymbolKind::Method => 'm',
+        SymbolKind::Class => 'c',
+        SymbolKind::Struct => 's',
+        SymbolKind::Enum => 'g',
+        SymbolKind::Interface => 'i',
+        SymbolKind::Module => 'n',
+        SymbolKind::Impl => 'I',
+        SymbolKind::Type => 't',
+        SymbolKind::Constant => 'C',
+        SymbolKind::Macro => 'd',
+    }
+}
+
+fn ctags_pattern(line: &str) -> String {
+    let mut res = String::from("/^");
+    for c in line.chars() {
+        match c {
+            '\\' | '/' => {
+                res.push('\\');
+                res.push(c);
+            }
+            c => res.push(c),
+        }
+    }
+    res.push_str("$/");
+    res
+}
+
+// Writes a Universal-ctags compatible file, sorted by tag name. References are written with a
+// `roles:ref` field, the same way `ctags --extras=+r` does.
+pub fn write_ctags<W: Write>(out: &mut W, files: &[TaggedFile]) -> io::Result<()> {
+    let mut lines = Vec::new();
+    for file in files {
+        for tag in &file.tags {
+            let roles = if tag.is_definition { "def" } else { "ref" };
+            lines.push(format!("{}\t{}\t{};\"\t{}\tline:{}\troles:{}",
+                tag.name,
+                file.path,
+                ctags_pattern(&tag.line),
+                ctags_letter(tag.kind),
+                tag.row + 1,
+                roles));
+        }
+    }
+    lines.sort();
+
+    writeln!(out, "!_TAG_FILE_FORMAT\t2\t/extended format; --format=1 will not append ;\" to lines/")?;
+    writeln!(out, "!_TAG_FILE_SORTED\t1\t/0=unsorted, 1=sorted, 2=foldcase/")?;
+    writeln!(out, "!_TAG_PROGRAM_NAME\thornbeam\t//")?;
+    for line in lines {
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
+
+// Writes an Emacs TAGS file. Etags has no notion of references, so only definitions are included.
+pub fn write_etags<W: Write>(out: &mut W, files: &[TaggedFile]) -> io::Result<()> {
+    for file in files {
+        let mut section = String::new();
+        for tag in file.tags.iter().filter(|t| t.is_definition) {
+            section.push_str(&format!("{}\x7f{}\x01{},{}\n", tag.prefix, tag.name, tag.row + 1, tag.line_start));
+        }
+        write!(out, "\x0c\n{},{}\n{}", file.path, section.len(), section)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{Parser, Language};
+
+    #[test]
+    fn test_python_tags() {
+        let text = "class A:\n    def f(self):\n        g(1)\n\ndef g(x):\n    pass\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let tags = Tag::extract(&tree);
+
+        let summary = tags.iter()
+            .map(|t| (t.name.as_str(), t.kind, t.is_definition, t.row))
+            .collect::<Vec<_>>();
+
+        assert_eq!(summary, vec![
+            ("A", SymbolKind::Class, true, 0),
+            ("f", SymbolKind::Method, true, 1),
+            ("g", SymbolKind::Function, false, 2),
+            ("g", SymbolKind::Function, true, 4),
+        ]);
+
+        let mut out = Vec::new();
+        let files = [TaggedFile { path: "a.py".to_string(), tags }];
+        write_ctags(&mut out, &files).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("f\ta.py\t/^    def f(self):$/;\"\tm\tline:2\troles:def\n"));
+    }
+
+    fn definitions(lang: Language, text: &str) -> Vec<(String, SymbolKind, usize)> {
+        let tree = Parser::new(lang).parse(text).unwrap();
+        Tag::extract(&tree).into_iter()
+            .filter(|t| t.is_definition)
+            .map(|t| (t.name, t.kind, t.row))
+            .collect()
+    }
+
+    #[test]
+    #[cfg(feature = "lang_c")]
+    fn test_c_tags() {
+        let text = "foo_t make_foo(void) {}\nstruct foo *get(struct bar b) { return 0; }\ntypedef foo_t bar_t;\ntypedef int (*cb_t)(int);\nstruct s { int a; };\n";
+        assert_eq!(definitions(Language::C, text), vec![
+            ("make_foo".to_string(), SymbolKind::Function, 0),
+            ("get".to_string(), SymbolKind::Function, 1),
+            ("bar_t".to_string(), SymbolKind::Type, 2),
+            ("cb_t".to_string(), SymbolKind::Type, 3),
+            ("s".to_string(), SymbolKind::Struct, 4),
+        ]);
+    }
+
+    #[test]
+    #[cfg(feature = "lang_java")]
+    fn test_java_tags() {
+        let text = "class A {\n    Foo make(int n) { return null; }\n    List<Foo> all() { return null; }\n    A() {}\n}\n";
+        assert_eq!(definitions(Language::Java, text), vec![
+            ("A".to_string(), SymbolKind::Class, 0),
+            ("make".to_string(), SymbolKind::Method, 1),
+            ("all".to_string(), SymbolKind::Method, 2),
+            ("A".to_string(), SymbolKind::Method, 3),
+        ]);
+    }
+
+    #[test]
+    #[cfg(feature = "lang_csharp")]
+    fn test_csharp_tags() {
+        let text = "class A {\n    Foo Make(int n) { return null; }\n    A() {}\n}\n";
+        assert_eq!(definitions(Language::CSharp, text), vec![
+            ("A".to_string(), SymbolKind::Class, 0),
+            ("Make".to_string(), SymbolKind::Method, 1),
+            ("A".to_string(), SymbolKind::Method, 2),
+        ]);
+    }
+
+    #[test]
+    #[cfg(feature = "lang_rust")]
+    fn test_rust_impl_tags() {
+        let text = "impl<T> Foo<T> {}\nimpl<T> Display for Foo<T> {}\nimpl fmt::Debug for Bar {}\nimpl Baz for a::Qux {}\n";
+        let tree = Parser::new(Language::Rust).parse(text).unwrap();
+        let tags = Tag::extract(&tree);
+
+        let impls = tags.iter()
+            .filter(|t| t.is_definition)
+            .map(|t| (t.name.as_str(), t.row))
+            .collect::<Vec<_>>();
+
+        assert_eq!(impls, vec![("Foo", 0), ("Foo", 1), ("Bar", 2), ("Qux", 3)]);
+    }
+}
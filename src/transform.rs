@@ -1,4 +1,5 @@
 use crate::parse::{Parser, Kind, Language, Node, Child};
+use crate::trivia::Comments;
 use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::Read;
@@ -68,6 +69,28 @@ impl Variadic {
         assert!(self.repeat == Repeat::Single);
         self.pattern.replace(vars)
     }
+
+    fn is_layout(&self) -> bool {
+        match &self.pattern {
+            Pattern::TextLiteral(text) => text.trim().is_empty(),
+            _ => false,
+        }
+    }
+}
+
+fn is_comment(node: &Node) -> bool {
+    node.kind_name().contains("comment")
+}
+
+// The whitespace directly before and after `node`.
+fn space_before<'a>(text: &'a str, node: &Node) -> &'a str {
+    let before = &text[..node.start_byte()];
+    &before[before.trim_end().len()..]
+}
+
+fn space_after<'a>(text: &'a str, node: &Node) -> &'a str {
+    let after = &text[node.end_byte()..];
+    &after[..after.len() - after.trim_start().len()]
 }
 
 fn has_var<'a>(node: &Node<'a>, vars: &[&str]) -> bool {
@@ -106,14 +129,18 @@ impl Pattern {
         }
     }
 
-    fn check<'a>(&self, node: &Node<'a>, vars: &mut Option<&'a str>) -> bool {
+    fn check<'a>(&self, node: &Node<'a>, vars: &mut Option<Node<'a>>) -> bool {
         println!("checking {:?} vs {:?}", self, node);
         match self {
             Pattern::Node { kind, children, } => {
                 if node.kind() == *kind {
-                    let mut it = node.children();
+                    // Comments and layout don't change what the code means.
+                    let mut it = node.children().filter(|ch| match ch {
+                        Child::Node(n) => !is_comment(n),
+                        Child::Text(text) => !text.trim().is_empty(),
+                    });
 
-                    for ch in children {
+                    for ch in children.iter().filter(|ch| !ch.is_layout()) {
                         assert!(ch.repeat == Repeat::Single);
                         if let Some(nch) = it.next() {
                             match nch {
@@ -157,7 +184,7 @@ impl Pattern {
                 if vars.is_some() {
                     panic!();
                 } else {
-                    *vars = Some(node.text());
+                    *vars = Some(node.clone());
                 }
                 true
             }
@@ -190,11 +217,12 @@ impl Rule {
         }
     }
 
-    fn check(&self, node: &Node) -> Option<String> {
+    // The node bound to the variable, if `node` matches.
+    fn check<'a>(&self, node: &Node<'a>) -> Option<Option<Node<'a>>> {
         let mut vars = None;
 
         if self.pattern.check(node, &mut vars) {
-            Some(self.output.replace(&vars))
+            Some(vars)
         } else {
             None
         }
@@ -204,10 +232,10 @@ impl Rule {
 impl Program {
     pub fn parse(from_lang: Language, to_lang: Language, from: &str, to: &str, vars: &[&str]) -> Program {
         let mut from_parser = Parser::new(from_lang);
-        let from_tree = from_parser.parse(from);
+        let from_tree = from_parser.parse(from).unwrap();
 
         let mut to_parser = Parser::new(to_lang);
-        let to_tree = to_parser.parse(to);
+        let to_tree = to_parser.parse(to).unwrap();
 
         let rule = Rule::parse(&from_tree.root(), &to_tree.root(), vars);
 
@@ -220,13 +248,60 @@ impl Program {
 
     pub fn apply(&self, text: &str) -> Option<String> {
         let mut parser = Parser::new(self.from_lang);
-        let tree = parser.parse(text);
+        let tree = parser.parse(text).unwrap();
+        let comments = Comments::attach(&tree);
+
+        // Comments are written in the syntax of `from_lang`, so they can't come along into
+        // another language.
+        if self.from_lang != self.to_lang && !comments.all().is_empty() {
+            return self.apply(&comments.strip());
+        }
+
         let root = &tree.root();
 
         for rule in &self.rules {
-            if let Some(res) = rule.check(root) {
+            if let Some(var) = rule.check(root) {
                 // TODO: check that result parses correctly under self.to_lang
-                return Some(res)
+
+                // Comments attached to the node bound to the variable go with it, and those
+                // inside it are part of its text. The rest were attached to code the rule
+                // rewrote, so they go before or after the whole result.
+                let inside = |c: &Node| matches!(&var, Some(v) if v.start_byte() <= c.start_byte() && c.end_byte() <= v.end_byte());
+                let var_text = var.as_ref().map(|v| {
+                    let mut res = String::new();
+                    for c in comments.leading(v) {
+                        res.push_str(c.text());
+                        res.push_str(space_after(text, c));
+                    }
+                    res.push_str(v.text());
+                    for c in comments.trailing(v) {
+                        res.push_str(space_before(text, c));
+                        res.push_str(c.text());
+                    }
+                    if let Some(c) = comments.trailing(v).last() {
+                        res.push_str(space_after(text, c));
+                    }
+                    res
+                });
+                let attached = var.as_ref().map_or(Vec::new(), |v| {
+                    comments.leading(v).iter().chain(comments.trailing(v)).collect::<Vec<_>>()
+                });
+                let rest = comments.all().iter()
+                    .filter(|c| !inside(c) && !attached.iter().any(|a| a.start_byte() == c.start_byte()))
+                    .collect::<Vec<_>>();
+
+                let mut out = text[..text.len() - text.trim_start().len()].to_string();
+                for c in rest.iter().filter(|c| comments.is_leading(c)) {
+                    out.push_str(c.text());
+                    out.push_str(space_after(text, c));
+                }
+                out.push_str(&rule.output.replace(&var_text.as_deref()));
+                for c in rest.iter().filter(|c| !comments.is_leading(c)) {
+                    out.push_str(space_before(text, c));
+                    out.push_str(c.text());
+                }
+                out.push_str(&text[text.trim_end().len()..]);
+                return Some(out)
             }
         }
         None
@@ -307,7 +382,7 @@ impl Transform {
             .into_iter().map(|l| (l, Parser::new(l))).collect::<HashMap<Language, Parser>>();
 
         for ex in &examples.list {
-            let tree = parsers.get_mut(&ex.lang).unwrap().parse(&ex.text);
+            let tree = parsers.get_mut(&ex.lang).unwrap().parse(&ex.text).unwrap();
             dbg!(tree);
         }
 
@@ -332,4 +407,22 @@ mod tests {
         let r = p.apply("fn abcd() {}");
         assert_eq!(r, Some(String::from("function abcd() {}")));
     }
+
+    #[test]
+    fn test_pattern_comments() {
+        let p = Program::parse(Language::Python, Language::Python, "f(a)", "g(a)", &["a"]);
+        assert_eq!(p.apply("# lead\nf(x)  # trail"), Some(String::from("# lead\ng(x)  # trail")));
+        assert_eq!(p.apply("f(x)  # a\n# b\n"), Some(String::from("g(x)  # a\n# b\n")));
+        assert_eq!(p.apply("f(x  # the arg\n)"), Some(String::from("g(x  # the arg\n)")));
+        assert_eq!(p.apply("f(  # lead\n  x)"), Some(String::from("g(# lead\n  x)")));
+        assert_eq!(p.apply("f([1, # one\n   2])"), Some(String::from("g([1, # one\n   2])")));
+        assert_eq!(p.apply("f(x)  # only code counts\nh(y)"), None);
+    }
+
+    #[test]
+    #[cfg(feature = "lang_javascript")]
+    fn test_pattern_comments_cross() {
+        let p = Program::parse(Language::Python, Language::Javascript, "f(a)", "g(a);", &["a"]);
+        assert_eq!(p.apply("# lead\nf(x  # the arg\n)  # trail\n"), Some(String::from("g(x);\n")));
+    }
 }
@@ -0,0 +1,204 @@
+use crate::parse::{Tree, Node, Kind};
+use std::collections::{HashMap, HashSet};
+
+// The comments and blank lines that belong with a node, so that moving or rewriting the node
+// can bring them along.
+#[derive(Debug, Clone, Default)]
+pub struct Trivia<'a> {
+    // Comments on the lines directly before the node, e.g. a doc comment.
+    pub leading: Vec<Node<'a>>,
+    // Comments after the node on its last line, and comments at the end of a block that have no
+    // following node to lead.
+    pub trailing: Vec<Node<'a>>,
+    // Blank lines before the node, or before its first leading comment.
+    pub blank_lines_before: usize,
+}
+
+type NodeKey = (usize, usize, Kind);
+
+fn key(node: &Node) -> NodeKey {
+    (node.start_byte(), node.end_byte(), node.kind())
+}
+
+fn is_comment(node: &Node) -> bool {
+    node.kind_name().contains("comment")
+}
+
+pub struct Comments<'a> {
+    text: &'a str,
+    all: Vec<Node<'a>>,
+    attached: HashMap<NodeKey, Trivia<'a>>,
+    leading: HashSet<NodeKey>,
+    trailing: HashSet<NodeKey>,
+    // Comments with no sibling to attach to, keyed by their parent, e.g. in an empty block.
+    dangling: HashMap<NodeKey, Vec<Node<'a>>>,
+}
+
+impl<'a> Comments<'a> {
+    pub fn attach(tree: &'a Tree<'a>) -> Comments<'a> {
+        let mut comments = Comments {
+            text: tree.text(),
+            all: Vec::new(),
+            attached: HashMap::new(),
+            leading: HashSet::new(),
+            trailing: HashSet::new(),
+            dangling: HashMap::new(),
+        };
+        comments.collect(&tree.root());
+        comments
+    }
+
+    fn collect(&mut self, node: &Node<'a>) {
+        let children = node.nodes().collect::<Vec<_>>();
+        let is_target = |n: &Node| n.is_named() && !is_comment(n);
+
+        let mut trivia: HashMap<usize, Trivia<'a>> = HashMap::new();
+        let mut prev: Option<usize> = None;
+
+        for (i, child) in children.iter().enumerate() {
+            if !is_comment(child) {
+                if is_target(child) {
+                    prev = Some(i);
+                }
+                self.collect(child);
+                continue;
+            }
+
+            self.all.push(child.clone());
+            let next = (i + 1..children.len()).find(|&j| is_target(&children[j]));
+
+            match (prev, next) {
+                (Some(p), _) if children[p].end_point().row == child.start_point().row => {
+                    trivia.entry(p).or_default().trailing.push(child.clone());
+                    self.trailing.insert(key(child));
+                }
+                (_, Some(n)) => {
+                    trivia.entry(n).or_default().leading.push(child.clone());
+                    self.leading.insert(key(child));
+                }
+                (Some(p), None) => trivia.entry(p).or_default().trailing.push(child.clone()),
+                (None, None) => self.dangling.entry(key(node)).or_default().push(child.clone()),
+            }
+        }
+
+        for (i, child) in children.iter().enumerate().skip(1).filter(|(_, c)| is_target(c)) {
+            let start = trivia.get(&i)
+                .and_then(|t| t.leading.first())
+                .map_or(child.start_byte(), |c| c.start_byte());
+            let prev_end = children[..i].iter()
+                .map(|c| c.end_byte())
+                .filter(|&end| end <= start)
+                .max()
+                .unwrap_or(start);
+
+            let blank_lines = self.text[prev_end..start].matches('\n').count().saturating_sub(1);
+            if blank_lines > 0 {
+                trivia.entry(i).or_default().blank_lines_before = blank_lines;
+            }
+        }
+
+        for (i, t) in trivia {
+            self.attached.insert(key(&children[i]), t);
+        }
+    }
+
+    pub fn get(&self, node: &Node) -> Option<&Trivia<'a>> {
+        self.attached.get(&key(node))
+    }
+
+    pub fn leading(&self, node: &Node) -> &[Node<'a>] {
+        self.get(node).map_or(&[], |t| &t.leading)
+    }
+
+    pub fn trailing(&self, node: &Node) -> &[Node<'a>] {
+        self.get(node).map_or(&[], |t| &t.trailing)
+    }
+
+    pub fn dangling(&self, node: &Node) -> &[Node<'a>] {
+        self.dangling.get(&key(node)).map_or(&[], |c| c.as_slice())
+    }
+
+    // Every comment in the tree, in order.
+    pub fn all(&self) -> &[Node<'a>] {
+        &self.all
+    }
+
+    // Whether `comment` leads the node after it, rather than trailing one or dangling.
+    pub fn is_leading(&self, comment: &Node) -> bool {
+        self.leading.contains(&key(comment))
+    }
+
+    // Whether `comment` follows code on its line.
+    pub fn is_trailing(&self, comment: &Node) -> bool {
+        self.trailing.contains(&key(comment))
+    }
+
+    // The byte range of `node` together with its leading and trailing comments; this is what to
+    // cut when moving a function along with its doc comment.
+    pub fn extent(&self, node: &Node) -> (usize, usize) {
+        let start = self.leading(node).first().map_or(node.start_byte(), |c| c.start_byte());
+        let end = self.trailing(node).last().map_or(node.end_byte(), |c| c.end_byte());
+        (start, end)
+    }
+
+    // The text with every comment removed. A comment on a line of its own takes the line with it,
+    // and one between two tokens leaves a space so they don't run together.
+    pub fn strip(&self) -> String {
+        let text = self.text;
+        let mut res = String::new();
+        let mut pos = 0;
+
+        for comment in &self.all {
+            let mut start = comment.start_byte();
+            let mut end = comment.end_byte();
+            while start > pos && (text.as_bytes()[start - 1] == b' ' || text.as_bytes()[start - 1] == b'\t') {
+                start -= 1;
+            }
+
+            let at_line_start = start == 0 || text.as_bytes()[start - 1] == b'\n';
+            let at_line_end = end == text.len() || text[end..].starts_with('\n');
+            if at_line_start && text[end..].starts_with('\n') {
+                end += 1;
+            }
+
+            res.push_str(&text[pos..start]);
+            if start == comment.start_byte() && !at_line_start && !at_line_end {
+                res.push(' ');
+            }
+            pos = end;
+        }
+
+        res.push_str(&text[pos..]);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{Parser, Language};
+
+    #[test]
+    fn test_python_comments() {
+        let text = "import os\n\n\n# Doc for f.\n# More.\ndef f():\n    return 1  # one\n\nx = 2\n# end\n";
+        let tree = Parser::new(Language::Python).parse(text).unwrap();
+        let comments = Comments::attach(&tree);
+        let root = tree.root();
+        let nodes = root.nodes().filter(|n| !is_comment(n)).collect::<Vec<_>>();
+
+        let f = &nodes[1];
+        assert_eq!(comments.leading(f).iter().map(|c| c.text()).collect::<Vec<_>>(), vec!["# Doc for f.", "# More."]);
+        assert_eq!(comments.get(f).unwrap().blank_lines_before, 2);
+
+        let (start, end) = comments.extent(f);
+        assert_eq!(&text[start..end], "# Doc for f.\n# More.\ndef f():\n    return 1  # one");
+        assert!(comments.is_leading(&comments.all()[0]));
+        assert!(comments.is_trailing(&comments.all()[2]));
+
+        let x = &nodes[2];
+        assert_eq!(comments.trailing(x)[0].text(), "# end");
+        assert_eq!(comments.get(x).unwrap().blank_lines_before, 1);
+
+        assert_eq!(comments.strip(), "import os\n\n\ndef f():\n    return 1\n\nx = 2\n");
+    }
+}